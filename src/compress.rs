@@ -17,14 +17,103 @@
 
 use super::cursor;
 use super::mempool;
+use super::parser;
 use super::zlib;
 
 use std::collections::VecDeque;
 
 use bytes::Buf;
 
+#[derive(Debug, PartialEq)]
+pub enum SizedProcessError {
+    ZLibError(zlib::ZLibError),
+    /// zlib produced more output than was expected
+    OutputOverrun,
+    /// zlib ran out of input before producing the expected output. usize is how much was produced.
+    OutputUnderrun(usize),
+}
+
+impl std::fmt::Display for SizedProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizedProcessError::ZLibError(e) => e.fmt(f),
+            SizedProcessError::OutputOverrun => f.write_str("output is larger than expected"),
+            SizedProcessError::OutputUnderrun(n) => {
+                write!(f, "output is smaller than expected, at {} bytes", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SizedProcessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SizedProcessError::ZLibError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<zlib::ZLibError> for SizedProcessError {
+    fn from(z: zlib::ZLibError) -> SizedProcessError {
+        SizedProcessError::ZLibError(z)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    ZLibError(zlib::ZLibError),
+    /// A page from the allocator is too small to hold the data length header
+    HeaderTooLarge(usize),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::ZLibError(e) => e.fmt(f),
+            FrameError::HeaderTooLarge(n) => {
+                write!(f, "data length header of {} bytes doesn't fit in a page", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::ZLibError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<zlib::ZLibError> for FrameError {
+    fn from(z: zlib::ZLibError) -> FrameError {
+        FrameError::ZLibError(z)
+    }
+}
+
+/// The size of the scratch buffer process_streaming hands output to its sink through
+const STREAMING_CHUNK: usize = 4096;
+
+/// The most a sync flush adds to the output. Whatever came before it has to end its block, which
+/// at worst is stored - up to 10 bits of header and padding then 4 bytes of length - and then the
+/// empty stored block marking the flush takes as much again.
+const FLUSH_OVERHEAD: usize = 11;
+
+// Takes the next page of input with anything in it, dropping any empty pages ahead of it
+fn next_input<T: cursor::DirectBuf>(b: &mut cursor::Multibytes<T>) -> Option<T> {
+    while let Some(page) = b.b.pop_front() {
+        if page.has_remaining() {
+            return Some(page);
+        }
+    }
+    None
+}
+
 pub struct MbZlibOp<Op: zlib::ZlibOperator> {
     z: Op,
+    concatenated: bool,
 }
 
 pub type Inflater = MbZlibOp<zlib::Inflate>;
@@ -33,14 +122,83 @@ pub type Deflater = MbZlibOp<zlib::Deflate>;
 impl MbZlibOp<zlib::Deflate> {
     pub fn deflate(level: i32) -> Result<Self, zlib::ZLibError> {
         let deflate = zlib::Deflate::new(level)?;
-        Ok(MbZlibOp { z: deflate })
+        Ok(MbZlibOp {
+            z: deflate,
+            concatenated: false,
+        })
+    }
+
+    /// Prepends the compression header to a packet body. Bodies of at least threshold bytes are
+    /// deflated as a stream of their own, and anything shorter is passed along as is with a data
+    /// length of 0. The header goes in a page of its own, so this fails if a page from alloc is too
+    /// small to hold it.
+    pub fn frame_compressed<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
+        &mut self,
+        body: cursor::Multibytes<T>,
+        threshold: i32,
+        alloc: &'a Alloc,
+    ) -> Result<cursor::Multibytes<T>, FrameError> {
+        let uncompressed_len = body.remaining();
+        let compress = uncompressed_len > 0 && uncompressed_len as i64 >= threshold as i64;
+        let data_len = if compress { uncompressed_len as i32 } else { 0 };
+
+        // Write the header first so a page too small for it fails before anything is deflated
+        let mut header = alloc.allocate();
+        let len = parser::encode_varint(data_len, &mut header.as_mut());
+        if len == 0 {
+            return Err(FrameError::HeaderTooLarge(parser::varint_len(data_len)));
+        }
+        header.truncate(len);
+
+        let mut data = if compress {
+            self.reset();
+            self.process(body, alloc)?
+        } else {
+            body
+        };
+        data.prepend(cursor::Multibytes::from_buf(header));
+        Ok(data)
+    }
+
+    /// The worst case size that source_len bytes of input, spread over pages pages, could deflate
+    /// to through process. Every page is flushed on its own, and each flush can cost up to
+    /// FLUSH_OVERHEAD bytes on top of what zlib bounds the data itself to.
+    pub fn bound(&self, source_len: usize, pages: usize) -> usize {
+        zlib::deflate_bound(&self.z.strm, source_len) + pages * FLUSH_OVERHEAD
+    }
+
+    /// Bytes and bits of output which have been generated but are still held inside zlib
+    pub fn pending(&self) -> (usize, u32) {
+        self.z.pending()
     }
 }
 
 impl MbZlibOp<zlib::Inflate> {
     pub fn inflate() -> Result<Self, zlib::ZLibError> {
         let inflate = zlib::Inflate::new()?;
-        Ok(MbZlibOp { z: inflate })
+        Ok(MbZlibOp {
+            z: inflate,
+            concatenated: false,
+        })
+    }
+
+    /// When set, input which carries on past the end of a stream is taken to be the start of
+    /// another, and the inflated output of each stream is joined together. Otherwise, any input
+    /// past the end of the stream is a DataError.
+    pub fn set_concatenated(&mut self, concatenated: bool) {
+        self.concatenated = concatenated;
+    }
+
+    /// Whether the input processed so far ended on a flush boundary (or the end of the stream),
+    /// meaning the output is a complete unit. If not, the data was cut off partway through and
+    /// there is more to come in the next process. zlib reports the same boundary right after the
+    /// stream header, so nothing counts as complete until some output has come of it.
+    pub fn at_flush_boundary(&self) -> bool {
+        use zlib::ZlibOperator;
+
+        let strm = self.z.strm();
+        strm.avail_in == 0
+            && (self.z.stream_ended() || (strm.total_out > 0 && self.z.at_block_boundary()))
     }
 }
 
@@ -57,12 +215,57 @@ impl<Op: zlib::ZlibOperator> MbZlibOp<Op> {
         self.z.strm_mut().avail_out = b.len() as u32;
     }
 
+    /// Throws away all stream state, so that the next process starts on a fresh stream
+    pub fn reset(&mut self) {
+        self.z.reset()
+    }
+
+    /// The running checksum zlib keeps of the uncompressed data - for a deflater this covers all
+    /// input consumed, and for an inflater all output produced since the last reset.
+    pub fn checksum(&self) -> u32 {
+        self.z.strm().adler as u32
+    }
+
+    /// Runs zlib once over whatever input and output it has been given
+    fn step(&mut self) -> Result<(), zlib::ZLibError> {
+        if self.z.stream_ended() && self.z.strm().avail_in > 0 {
+            // zlib won't touch input past the end of a stream, so without this the caller would
+            // spin forever waiting on it to be consumed
+            if !self.concatenated {
+                return Err(zlib::ZLibError::DataError);
+            }
+            self.z.reset();
+        }
+
+        let (total_in, total_out) = (self.z.strm().total_in, self.z.strm().total_out);
+        match self.z.process(zlib::FlushMode::SyncFlush) {
+            None if self.z.strm().total_in == total_in
+                && self.z.strm().total_out == total_out
+                && self.z.strm().avail_in > 0
+                && self.z.strm().avail_out > 0 =>
+            {
+                // zlib claims success but can't get anywhere with what it has, as happens with
+                // streams asking for a preset dictionary. Trying again would go nowhere forever.
+                Err(zlib::ZLibError::DataError)
+            }
+            None => Ok(()),
+            Some(zlib::ZLibError::BufError)
+                if self.z.strm().avail_in == 0 || self.z.strm().avail_out == 0 =>
+            {
+                // zlib couldn't make any progress, but only because it is out of input or output
+                // space - both of which are for the caller to replenish. This isn't fatal.
+                Ok(())
+            }
+            Some(err) => Err(err),
+        }
+    }
+
     pub fn process<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
         &mut self,
         mut b: cursor::Multibytes<T>,
         alloc: &'a Alloc,
     ) -> Result<cursor::Multibytes<T>, zlib::ZLibError> {
-        let mut buf_in = match b.b.pop_front() {
+        let mut buf_in = match next_input(&mut b) {
             Some(x) => x,
             None => return Ok(b), // Nothing to do, abort!
         };
@@ -80,29 +283,56 @@ impl<Op: zlib::ZlibOperator> MbZlibOp<Op> {
         let mut vd = VecDeque::new();
 
         loop {
-            if let Some(err) = self.z.process(zlib::FlushMode::SyncFlush) {
-                return Err(err);
-            }
+            self.step()?;
 
-            if self.z.strm().avail_in == 0 {
-                // Try to pop again
-                if let Some(new_buf_in) = b.b.pop_front() {
-                    buf_in = new_buf_in;
+            if self.z.strm().avail_out == 0 {
+                if self.z.strm().avail_in == 0 && b.b.is_empty() {
+                    // All of the input is in, so the only output left is whatever zlib is still
+                    // holding onto, if anything. Check with a single byte of room before
+                    // allocating a whole buffer which would otherwise be thrown straight away.
+                    let mut probe = [0u8; 1];
+                    self.z.strm_mut().next_out = probe.as_mut_ptr();
+                    self.z.strm_mut().avail_out = 1;
+                    self.step()?;
+                    if self.z.strm().avail_out == 1 {
+                        vd.push_back(buf_out);
+                        return Ok(cursor::Multibytes::new(vd));
+                    }
+
+                    let old_buf = std::mem::replace(&mut buf_out, alloc.allocate());
+                    vd.push_back(old_buf);
+                    buf_out.as_mut()[0] = probe[0];
                     unsafe {
-                        self.set_in(&buf_in);
+                        self.set_out(&mut buf_out);
+                        let strm = self.z.strm_mut();
+                        strm.next_out = strm.next_out.add(1);
+                        strm.avail_out -= 1;
                     }
-                } else {
-                    break;
+                    continue;
                 }
-            }
 
-            if self.z.strm().avail_out == 0 {
                 let old_buf = std::mem::replace(&mut buf_out, alloc.allocate());
                 unsafe {
                     self.set_out(&mut buf_out);
                 }
 
                 vd.push_back(old_buf);
+
+                // zlib may still be holding onto output it had no room for - let it flush that
+                // before we decide whether we are out of input.
+                continue;
+            }
+
+            if self.z.strm().avail_in == 0 {
+                // Try to pop again
+                if let Some(new_buf_in) = next_input(&mut b) {
+                    buf_in = new_buf_in;
+                    unsafe {
+                        self.set_in(&buf_in);
+                    }
+                } else {
+                    break;
+                }
             }
         }
 
@@ -115,6 +345,284 @@ impl<Op: zlib::ZlibOperator> MbZlibOp<Op> {
 
         Ok(cursor::Multibytes::new(vd))
     }
+
+    /// Ends the stream, returning whatever output zlib was still holding on to. A deflater writes
+    /// out the end of the stream (and its checksum), while an inflater fails with BufError if the
+    /// stream it was given hasn't actually ended. The operator is torn down afterwards.
+    pub fn finish<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
+        mut self,
+        alloc: &'a Alloc,
+    ) -> Result<cursor::Multibytes<T>, zlib::ZLibError> {
+        // All of the input has gone in already
+        self.z.strm_mut().avail_in = 0;
+
+        let mut vd = VecDeque::new();
+        loop {
+            let mut buf_out = alloc.allocate();
+            let room = buf_out.remaining();
+            unsafe {
+                self.set_out(&mut buf_out);
+            }
+
+            if let Some(e) = self.z.process(zlib::FlushMode::Finish) {
+                return Err(e);
+            }
+
+            let left = self.z.strm().avail_out as usize;
+            if left < room {
+                buf_out.truncate(room - left);
+                vd.push_back(buf_out);
+            }
+
+            // zlib only stops short of filling the output once it has finished the stream
+            if left > 0 {
+                return Ok(cursor::Multibytes::new(vd));
+            }
+        }
+    }
+
+    /// Like process, but rather than collecting the output, each chunk of it is handed to sink as
+    /// soon as zlib produces it. Output goes through a single scratch buffer, so nothing is
+    /// allocated no matter how large the output is.
+    pub fn process_streaming<T: cursor::DirectBufMut, F: FnMut(&[u8])>(
+        &mut self,
+        mut b: cursor::Multibytes<T>,
+        mut sink: F,
+    ) -> Result<(), zlib::ZLibError> {
+        let mut buf_in = match next_input(&mut b) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        unsafe {
+            self.set_in(&buf_in);
+        }
+
+        let mut out = [0u8; STREAMING_CHUNK];
+        loop {
+            self.z.strm_mut().next_out = out.as_mut_ptr();
+            self.z.strm_mut().avail_out = out.len() as u32;
+            self.step()?;
+
+            let produced = out.len() - self.z.strm().avail_out as usize;
+            if produced > 0 {
+                sink(&out[..produced]);
+            }
+
+            if self.z.strm().avail_out == 0 {
+                // There may be more output held inside zlib
+                continue;
+            }
+
+            if self.z.strm().avail_in == 0 {
+                if let Some(new_buf_in) = next_input(&mut b) {
+                    buf_in = new_buf_in;
+                    unsafe {
+                        self.set_in(&buf_in);
+                    }
+                } else {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Like process, but the input starts at the cursor rather than at the front of the first page.
+    /// Whatever comes before the cursor, such as a packet's headers, is dropped without being read.
+    pub fn process_at<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
+        &mut self,
+        mut b: cursor::Multibytes<T>,
+        start: cursor::Cursor,
+        alloc: &'a Alloc,
+    ) -> Result<cursor::Multibytes<T>, zlib::ZLibError> {
+        // Splitting just drops the pages before the cursor and moves the start of the first page
+        // up, so the input itself is never copied
+        b.split_to(&start);
+        self.process(b, alloc)
+    }
+
+    /// Like process, but the size of the output is known ahead of time. Output space is allocated
+    /// as zlib fills it, never more than expected_out in all, and anything other than exactly
+    /// expected_out bytes of output is an error.
+    pub fn process_sized<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
+        &mut self,
+        mut b: cursor::Multibytes<T>,
+        expected_out: usize,
+        alloc: &'a Alloc,
+    ) -> Result<cursor::Multibytes<T>, SizedProcessError> {
+        let mut buf_in = match next_input(&mut b) {
+            Some(x) => x,
+            None if expected_out == 0 => return Ok(b),
+            None => return Err(SizedProcessError::OutputUnderrun(0)),
+        };
+
+        let mut out = VecDeque::new();
+        // How much of expected_out hasn't been allocated yet
+        let mut left = expected_out;
+
+        // Once the real output is full, zlib is pointed here. If it manages to write into it,
+        // there was more output than expected.
+        let mut overrun = bytes::BytesMut::with_capacity(1);
+        unsafe { overrun.set_len(1) };
+        let mut overran = false;
+
+        unsafe {
+            self.set_in(&buf_in);
+        }
+
+        let mut need_out = true;
+        loop {
+            if need_out {
+                if overran {
+                    return Err(SizedProcessError::OutputOverrun);
+                }
+
+                if left > 0 {
+                    let mut page = alloc.allocate();
+                    if page.remaining() > left {
+                        page.truncate(left);
+                    }
+                    left -= page.remaining();
+                    out.push_back(page);
+                    unsafe { self.set_out(out.back_mut().unwrap()) };
+                } else {
+                    overran = true;
+                    unsafe { self.set_out(&mut overrun) };
+                }
+            }
+
+            self.step()?;
+
+            need_out = self.z.strm().avail_out == 0;
+            if need_out {
+                continue;
+            }
+
+            if self.z.strm().avail_in == 0 {
+                if let Some(new_buf_in) = next_input(&mut b) {
+                    buf_in = new_buf_in;
+                    unsafe {
+                        self.set_in(&buf_in);
+                    }
+                } else {
+                    break;
+                }
+            }
+        }
+
+        if !overran {
+            let unfilled = self.z.strm().avail_out as usize + left;
+            return Err(SizedProcessError::OutputUnderrun(expected_out - unfilled));
+        }
+
+        Ok(cursor::Multibytes::new(out))
+    }
+}
+
+/// Keeps Inflaters around once they are done with, so that connections which come and go don't
+/// each have to set up (and tear down) zlib's state and window from scratch. Inflaters are reset
+/// as they are released, so each one acquired starts on a fresh stream.
+pub struct InflatePool {
+    idle: Vec<Inflater>,
+    cap: usize,
+}
+
+impl InflatePool {
+    /// Holds onto at most cap idle Inflaters - any more than that released are dropped
+    pub fn new(cap: usize) -> InflatePool {
+        InflatePool {
+            idle: Vec::with_capacity(cap),
+            cap,
+        }
+    }
+
+    pub fn acquire(&mut self) -> Result<Inflater, zlib::ZLibError> {
+        match self.idle.pop() {
+            Some(i) => Ok(i),
+            None => Inflater::inflate(),
+        }
+    }
+
+    pub fn release(&mut self, mut i: Inflater) {
+        if self.idle.len() < self.cap {
+            i.reset();
+            i.set_concatenated(false);
+            self.idle.push(i);
+        }
+    }
+
+    /// The number of Inflaters waiting to be acquired
+    pub fn idle(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Deflates data as one complete zlib stream, for one-off jobs which don't need the pooled
+/// buffers of MbZlibOp
+pub fn deflate_bytes(data: &[u8], level: i32) -> Result<bytes::Bytes, zlib::ZLibError> {
+    use zlib::ZlibOperator;
+
+    let mut z = zlib::Deflate::new(level)?;
+    // The bound leaves enough room to finish in a single call
+    let mut out = bytes::BytesMut::with_capacity(zlib::deflate_bound(z.strm(), data.len()));
+    z.strm.next_in = data.as_ptr();
+    z.strm.avail_in = data.len() as _;
+    z.strm.next_out = out.as_mut_ptr();
+    z.strm.avail_out = out.capacity() as _;
+
+    if let Some(e) = z.process(zlib::FlushMode::Finish) {
+        return Err(e);
+    }
+    unsafe { out.set_len(z.strm.total_out) };
+    Ok(out.freeze())
+}
+
+/// Inflates data, which must be exactly one complete zlib stream, for one-off jobs which don't
+/// need the pooled buffers of MbZlibOp. A stream which inflates to more than max_out bytes is a
+/// BufError, as though the output buffer were only that big.
+pub fn inflate_bytes(data: &[u8], max_out: usize) -> Result<bytes::Bytes, zlib::ZLibError> {
+    use zlib::ZlibOperator;
+
+    let mut z = zlib::Inflate::new()?;
+    let mut out =
+        bytes::BytesMut::with_capacity(std::cmp::min(std::cmp::max(data.len() * 2, 64), max_out));
+    z.strm.next_in = data.as_ptr();
+    z.strm.avail_in = data.len() as _;
+
+    loop {
+        if out.len() == out.capacity() && out.len() < max_out {
+            let more = std::cmp::max(out.capacity(), 64);
+            out.reserve(std::cmp::min(more, max_out - out.len()));
+        }
+        // reserve may well hand back more than asked for, which zlib mustn't be let loose on
+        let spare = std::cmp::min(out.capacity(), max_out) - out.len();
+        unsafe {
+            z.strm.next_out = out.as_mut_ptr().add(out.len());
+        }
+        z.strm.avail_out = spare as _;
+
+        let err = z.process(zlib::FlushMode::NoFlush);
+        unsafe { out.set_len(out.len() + spare - z.strm.avail_out as usize) };
+
+        if z.stream_ended() {
+            // Anything past the end of the stream isn't part of it
+            if z.strm.avail_in > 0 {
+                return Err(zlib::ZLibError::DataError);
+            }
+            return Ok(out.freeze());
+        }
+        match err {
+            // Just out of room, which the next time around takes care of - unless there wasn't
+            // any room to begin with, in which case there is more output than max_out allows
+            None | Some(zlib::ZLibError::BufError) if z.strm.avail_out == 0 => {
+                if spare == 0 {
+                    return Err(zlib::ZLibError::BufError);
+                }
+            }
+            // With room to spare and yet no end to the stream, the input must be cut short
+            None | Some(zlib::ZLibError::BufError) => return Err(zlib::ZLibError::BufError),
+            Some(e) => return Err(e),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -133,6 +641,7 @@ pub mod tests {
                 buf_size: 8,
                 page_entries: 128,
                 concurrent_allocation_limit: 1,
+                ..Default::default()
             },
         );
 
@@ -144,9 +653,7 @@ pub mod tests {
             buffer[i] = (i % 16) as u8;
         }
 
-        let mut vd = VecDeque::new();
-        vd.push_back(buffer);
-        let mb = cursor::Multibytes::new(vd);
+        let mb = cursor::Multibytes::from_buf(buffer);
 
         let compressed = deflate.process(mb, &alloc).expect("could not deflate");
         assert_eq!(28, compressed.cursor().remaining(&compressed));
@@ -159,6 +666,664 @@ pub mod tests {
         }
     }
 
+    // Deflates data into a complete zlib stream of its own, trailer and all
+    fn finished_stream(data: &[u8]) -> Vec<u8> {
+        use zlib::ZlibOperator;
+
+        let mut z = zlib::Deflate::new(5).expect("could not init deflate");
+        let mut out = vec![0; zlib::deflate_bound(&z.strm, data.len())];
+        z.strm_mut().next_in = data.as_ptr();
+        z.strm_mut().avail_in = data.len() as u32;
+        z.strm_mut().next_out = out.as_mut_ptr();
+        z.strm_mut().avail_out = out.len() as u32;
+        assert_eq!(z.process(zlib::FlushMode::Finish), None);
+        let len = out.len() - z.strm().avail_out as usize;
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let alloc = mempool::SystemMemPool { buf_size: 6 };
+        let first: Vec<u8> = (0..200).map(|i| (i % 7) as u8).collect();
+        let second: Vec<u8> = (0..300).map(|i| (i % 13) as u8).collect();
+        let mut joined = finished_stream(&first);
+        joined.extend(finished_stream(&second));
+
+        // Split up so that the boundary between the streams lands mid-page
+        let mut input = cursor::Multibytes::new(VecDeque::new());
+        for chunk in joined.chunks(10) {
+            input.append(bytes::BytesMut::from(chunk));
+        }
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        inflate.set_concatenated(true);
+        let mut out = inflate.process(input, &alloc).expect("could not inflate");
+        let mut expected = first.clone();
+        expected.extend(&second);
+        assert_eq!(&out.to_bytes()[..], &expected[..]);
+
+        // Without asking for it, trailing data is corrupt rather than something to wait on
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let input = cursor::Multibytes::from_buf(bytes::BytesMut::from(&joined[..]));
+        assert_eq!(
+            inflate.process(input, &alloc).unwrap_err(),
+            zlib::ZLibError::DataError
+        );
+    }
+
+    #[test]
+    fn stalled_inflate() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        // A valid header which asks for a preset dictionary with an id of 1. zlib reports that it
+        // needs the dictionary without ever touching the data after it.
+        let input = cursor::Multibytes::from_buf(bytes::BytesMut::from(
+            &[0x78, 0xbb, 0x0, 0x0, 0x0, 0x1, 0x3, 0x0][..],
+        ));
+        assert_eq!(
+            inflate.process(input, &alloc).unwrap_err(),
+            zlib::ZLibError::DataError
+        );
+    }
+
+    #[test]
+    fn process_at_offset() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+        let body: Vec<u8> = (0..200).map(|i| (i % 9) as u8).collect();
+        let compressed = deflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&body[..])),
+                &alloc,
+            )
+            .expect("could not deflate");
+
+        // Three bytes of header share the first page with the start of the compressed body
+        let mut packet = cursor::Multibytes::from_buf(bytes::BytesMut::from(&[0x7, 0x8, 0x9][..]));
+        for page in compressed {
+            packet.append(page);
+        }
+        let mut input = cursor::Multibytes::new(VecDeque::new());
+        let joined = packet.to_bytes();
+        for chunk in joined.chunks(5) {
+            input.append(bytes::BytesMut::from(chunk));
+        }
+        let mut start = input.cursor();
+        start.advance(&input, 3);
+
+        let mut out = inflate
+            .process_at(input, start, &alloc)
+            .expect("could not inflate");
+        assert_eq!(&out.to_bytes()[..], &body[..]);
+    }
+
+    #[test]
+    fn inflate_streaming() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+        // Large enough to take several trips through the scratch buffer
+        let body: Vec<u8> = (0..3 * STREAMING_CHUNK + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut input = cursor::Multibytes::new(VecDeque::new());
+        for chunk in body.chunks(1000) {
+            input.append(bytes::BytesMut::from(chunk));
+        }
+        let compressed = deflate.process(input, &alloc).expect("could not deflate");
+
+        let mut out = Vec::new();
+        let mut calls = 0;
+        inflate
+            .process_streaming(compressed, |chunk| {
+                assert!(chunk.len() <= STREAMING_CHUNK);
+                out.extend_from_slice(chunk);
+                calls += 1;
+            })
+            .expect("could not inflate");
+        assert_eq!(out, body);
+        assert!(calls >= 4);
+    }
+
+    #[test]
+    fn flush_boundary() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        assert!(!inflate.at_flush_boundary());
+
+        let body: Vec<u8> = (0..300).map(|i| (i % 11) as u8).collect();
+        let mut compressed = deflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&body[..])),
+                &alloc,
+            )
+            .expect("could not deflate")
+            .to_bytes();
+        let second = compressed.split_off(compressed.len() / 2);
+
+        // Just the stream header, which leaves inflate waiting on the first block
+        let header = inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&compressed[..2])),
+                &alloc,
+            )
+            .expect("could not inflate")
+            .to_bytes();
+        assert!(header.is_empty());
+        assert!(!inflate.at_flush_boundary());
+
+        let mut out = inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&compressed[2..])),
+                &alloc,
+            )
+            .expect("could not inflate")
+            .to_bytes()
+            .to_vec();
+        assert!(!inflate.at_flush_boundary());
+
+        out.extend_from_slice(
+            &inflate
+                .process(
+                    cursor::Multibytes::from_buf(bytes::BytesMut::from(&second[..])),
+                    &alloc,
+                )
+                .expect("could not inflate")
+                .to_bytes(),
+        );
+        assert!(inflate.at_flush_boundary());
+        assert_eq!(out, body);
+    }
+
+    #[test]
+    fn frame_compressed_small_pages() {
+        // Two byte pages - enough for the header of a short packet, but not a long one
+        let alloc = mempool::SystemMemPool { buf_size: 1 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+
+        let mut short = deflate
+            .frame_compressed(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&[1, 2, 3][..])),
+                64,
+                &alloc,
+            )
+            .expect("could not frame");
+        assert_eq!(&short.to_bytes()[..], &[0, 1, 2, 3]);
+
+        let long = vec![7; 20000];
+        match deflate.frame_compressed(
+            cursor::Multibytes::from_buf(bytes::BytesMut::from(&long[..])),
+            64,
+            &alloc,
+        ) {
+            Err(e) => assert_eq!(e, FrameError::HeaderTooLarge(3)),
+            Ok(_) => panic!("framed a header too large for its page"),
+        }
+    }
+
+    #[test]
+    fn independent_packets() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let first: Vec<u8> = (0..200).map(|i| (i % 7) as u8).collect();
+        // Mostly a repeat of the first, so it would lean on it if it could
+        let mut second = first.clone();
+        second.extend_from_slice(b"and then some");
+
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut packets = Vec::new();
+        for body in [&first, &second].iter() {
+            let packet = deflate
+                .frame_compressed(
+                    cursor::Multibytes::from_buf(bytes::BytesMut::from(&body[..])),
+                    64,
+                    &alloc,
+                )
+                .expect("could not deflate");
+            let (packet, data_len) = parser::varint(packet).expect("no header");
+            assert_eq!(data_len as usize, body.len());
+            packets.push(packet);
+        }
+
+        // The second packet decodes on an inflater which never saw the first
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let mut out = inflate
+            .process(packets.pop().unwrap(), &alloc)
+            .expect("could not inflate");
+        assert_eq!(&out.to_bytes()[..], &second[..]);
+    }
+
+    #[test]
+    fn one_shot_bytes() {
+        let json = br#"{"version":{"name":"1.15.2","protocol":578},"players":{"max":20,"online":0},"description":{"text":"A Minecraft Server, A Minecraft Server, A Minecraft Server"}}"#;
+
+        let compressed = deflate_bytes(&json[..], 6).expect("could not deflate");
+        assert!(compressed.len() < json.len());
+        assert_eq!(
+            &inflate_bytes(&compressed, usize::MAX).expect("could not inflate")[..],
+            &json[..]
+        );
+
+        // Exactly as much room as it needs, and then one byte short
+        assert_eq!(
+            &inflate_bytes(&compressed, json.len()).expect("could not inflate")[..],
+            &json[..]
+        );
+        assert_eq!(
+            inflate_bytes(&compressed, json.len() - 1),
+            Err(zlib::ZLibError::BufError)
+        );
+
+        let empty = deflate_bytes(&[], 6).expect("could not deflate");
+        assert_eq!(
+            &inflate_bytes(&empty, 0).expect("could not inflate")[..],
+            &[]
+        );
+
+        // Cut short, and with trailing garbage
+        assert_eq!(
+            inflate_bytes(&compressed[..compressed.len() - 4], usize::MAX),
+            Err(zlib::ZLibError::BufError)
+        );
+        let mut trailing = compressed.to_vec();
+        trailing.push(0);
+        assert_eq!(
+            inflate_bytes(&trailing, usize::MAX),
+            Err(zlib::ZLibError::DataError)
+        );
+    }
+
+    #[test]
+    fn finish() {
+        let alloc = mempool::SystemMemPool { buf_size: 3 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+
+        let mut out = Vec::new();
+        for chunk in [&b"finish "[..], &b"him, "[..], &b"finish him"[..]].iter() {
+            let mut d = deflate
+                .process(
+                    cursor::Multibytes::from_buf(bytes::BytesMut::from(*chunk)),
+                    &alloc,
+                )
+                .expect("could not deflate");
+            out.extend_from_slice(&d.to_bytes());
+        }
+
+        // Without the end of the stream, this is only a sync flushed prefix
+        assert_eq!(
+            inflate_bytes(&out, usize::MAX),
+            Err(zlib::ZLibError::BufError)
+        );
+
+        let mut tail = deflate.finish(&alloc).expect("could not finish");
+        out.extend_from_slice(&tail.to_bytes());
+        assert_eq!(
+            &inflate_bytes(&out, usize::MAX).expect("could not inflate")[..],
+            &b"finish him, finish him"[..]
+        );
+
+        // The inflate side only finishes once the stream has ended
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let split = out.len() - 3;
+        inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&out[..split])),
+                &alloc,
+            )
+            .expect("could not inflate");
+        assert_eq!(
+            inflate.finish(&alloc).err(),
+            Some(zlib::ZLibError::BufError)
+        );
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let mut inflated = inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&out[..])),
+                &alloc,
+            )
+            .expect("could not inflate");
+        assert_eq!(
+            inflate
+                .finish(&alloc)
+                .expect("could not finish")
+                .remaining(),
+            0
+        );
+        assert_eq!(inflated.to_bytes(), &b"finish him, finish him"[..]);
+    }
+
+    #[test]
+    fn inflate_pool() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut pool = InflatePool::new(1);
+
+        let body: Vec<u8> = (0..300).map(|i| (i % 11) as u8).collect();
+        deflate.reset();
+        let compressed = deflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&body[..])),
+                &alloc,
+            )
+            .expect("could not deflate")
+            .to_bytes();
+
+        // Leave the first one partway through a stream
+        let mut inflate = pool.acquire().expect("could not acquire");
+        inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&compressed[..20])),
+                &alloc,
+            )
+            .expect("could not inflate");
+        pool.release(inflate);
+        assert_eq!(pool.idle(), 1);
+
+        // Only one is kept
+        pool.release(Inflater::inflate().expect("could not init inflate"));
+        assert_eq!(pool.idle(), 1);
+
+        let mut inflate = pool.acquire().expect("could not acquire");
+        assert_eq!(pool.idle(), 0);
+        let mut out = inflate
+            .process(
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&compressed[..])),
+                &alloc,
+            )
+            .expect("could not inflate");
+        assert_eq!(&out.to_bytes()[..], &body[..]);
+    }
+
+    #[test]
+    fn deflate_bound() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        // Cheap LCG so that some inputs don't compress well
+        let mut seed: u32 = 12345;
+
+        for len in [1, 16, 100, 1000, 4096].iter() {
+            for random in [false, true].iter() {
+                let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+                let bound = deflate.bound(*len, 1);
+
+                let mut buffer = alloc.allocate();
+                buffer.truncate(*len);
+                for i in 0..*len {
+                    buffer[i] = if *random {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        (seed >> 16) as u8
+                    } else {
+                        (i % 16) as u8
+                    };
+                }
+
+                let compressed = deflate
+                    .process(cursor::Multibytes::from_buf(buffer), &alloc)
+                    .expect("could not deflate");
+                assert!(bound >= compressed.cursor().remaining(&compressed));
+            }
+        }
+
+        // Many small pages which don't compress, each of which gets flushed on its own
+        let alloc = mempool::SystemMemPool { buf_size: 4 };
+        let mut vd = VecDeque::new();
+        for _ in 0..64 {
+            let mut buffer = alloc.allocate();
+            for i in 0..buffer.remaining() {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                buffer[i] = (seed >> 16) as u8;
+            }
+            vd.push_back(buffer);
+        }
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let bound = deflate.bound(64 * 16, 64);
+        let compressed = deflate
+            .process(cursor::Multibytes::new(vd), &alloc)
+            .expect("could not deflate");
+        assert!(bound >= compressed.cursor().remaining(&compressed));
+    }
+
+    #[test]
+    fn streaming_checksum() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let mut whole = zlib::Adler32::new();
+
+        for chunk in 0..2u8 {
+            let mut buffer = alloc.allocate();
+            for i in 0..buffer.remaining() {
+                buffer[i] = (i as u8) ^ chunk;
+            }
+            whole.update(&buffer);
+
+            let compressed = deflate
+                .process(cursor::Multibytes::from_buf(buffer), &alloc)
+                .expect("could not deflate");
+            inflate
+                .process(compressed, &alloc)
+                .expect("could not inflate");
+        }
+
+        assert_eq!(deflate.checksum(), whole.value());
+        assert_eq!(inflate.checksum(), whole.value());
+    }
+
+    fn counted_mb<'a>(
+        alloc: &'a mempool::CountingMemPool,
+        data: &[u8],
+    ) -> cursor::Multibytes<mempool::CountedBuf<'a>> {
+        let mut buffer = alloc.allocate();
+        cursor::DirectBuf::truncate(&mut buffer, data.len());
+        buffer.copy_from_slice(data);
+        cursor::Multibytes::from_buf(buffer)
+    }
+
+    #[test]
+    fn inflate_sized() {
+        let alloc = mempool::CountingMemPool::new(8);
+        let compressed = [120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11];
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let input = counted_mb(&alloc, &compressed);
+        let before = alloc.allocations();
+        let inflated = inflate
+            .process_sized(input, 4, &alloc)
+            .expect("could not inflate");
+        assert_eq!(alloc.allocations() - before, 1);
+        let mut v = inflated.view();
+        assert_eq!(v.get_u8(), 0x1);
+        assert_eq!(v.get_u8(), 0x2);
+        assert_eq!(v.get_u8(), 0x3);
+        assert_eq!(v.get_u8(), 0x4);
+        assert_eq!(v.remaining(), 0);
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        assert_eq!(
+            inflate
+                .process_sized(counted_mb(&alloc, &compressed), 3, &alloc)
+                .err()
+                .unwrap(),
+            SizedProcessError::OutputOverrun
+        );
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        assert_eq!(
+            inflate
+                .process_sized(counted_mb(&alloc, &compressed), 5, &alloc)
+                .err()
+                .unwrap(),
+            SizedProcessError::OutputUnderrun(4)
+        );
+
+        // Output space only comes out of the allocator as it is filled, however much is expected
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let before = alloc.allocations();
+        assert_eq!(
+            inflate
+                .process_sized(counted_mb(&alloc, &compressed), 1 << 30, &alloc)
+                .err()
+                .unwrap(),
+            SizedProcessError::OutputUnderrun(4)
+        );
+        assert_eq!(alloc.allocations() - before, 2);
+    }
+
+    #[test]
+    fn inflate_sized_multi_page() {
+        let alloc = mempool::SystemMemPool { buf_size: 4 };
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+        let mut vd = VecDeque::new();
+        for _ in 0..4 {
+            let mut buffer = alloc.allocate();
+            for i in 0..buffer.remaining() {
+                buffer[i] = i as u8;
+            }
+            vd.push_back(buffer);
+        }
+
+        let compressed = deflate
+            .process(cursor::Multibytes::new(vd), &alloc)
+            .expect("could not deflate");
+        let inflated = inflate
+            .process_sized(compressed, 64, &alloc)
+            .expect("could not inflate");
+        assert_eq!(inflated.page_count(), 4);
+        let mut v = inflated.view();
+        for i in 0..64 {
+            assert_eq!(v.get_u8(), (i % 16) as u8);
+        }
+    }
+
+    #[test]
+    fn leading_empty_pages() {
+        let alloc = mempool::SystemMemPool { buf_size: 8 };
+        let compressed = deflate_bytes(b"empty pages up front", 5).expect("could not deflate");
+        let (front, back) = compressed.split_at(4);
+
+        let mut mb = cursor::Multibytes::from_buf(bytes::BytesMut::new());
+        mb.append(bytes::BytesMut::new());
+        mb.append(bytes::BytesMut::from(front));
+        mb.append(bytes::BytesMut::new());
+        mb.append(bytes::BytesMut::from(back));
+
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        let mut inflated = inflate.process(mb, &alloc).expect("could not inflate");
+        assert_eq!(inflated.to_bytes(), &b"empty pages up front"[..]);
+
+        // With nothing but empty pages, there is nothing to do
+        let mut mb = cursor::Multibytes::from_buf(bytes::BytesMut::new());
+        mb.append(bytes::BytesMut::new());
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+        assert_eq!(
+            inflate
+                .process(mb, &alloc)
+                .expect("could not inflate")
+                .remaining(),
+            0
+        );
+    }
+
+    #[test]
+    fn inflate_tiny_output() {
+        let big = mempool::SystemMemPool { buf_size: 8 };
+        // zlib asks for more than 6 bytes of room when sync flushing a deflate stream, otherwise it
+        // will keep emitting flush markers
+        let small = mempool::SystemMemPool { buf_size: 3 };
+        let tiny = mempool::SystemMemPool { buf_size: 2 };
+
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+        let mut buffer = big.allocate();
+        for i in 0..buffer.remaining() {
+            buffer[i] = (i % 16) as u8;
+        }
+
+        let mb = cursor::Multibytes::from_buf(buffer);
+
+        // Small output pages mean zlib will regularly run out of room to make progress
+        let compressed = deflate.process(mb, &small).expect("could not deflate");
+        let reinflated = inflate
+            .process(compressed, &tiny)
+            .expect("could not inflate");
+        let mut v = reinflated.view();
+        for i in 0..=255 {
+            assert_eq!(i % 16 as u8, v.get_u8());
+        }
+        assert_eq!(v.remaining(), 0);
+    }
+
+    #[test]
+    fn no_wasted_output() {
+        let alloc = mempool::CountingMemPool::new(8);
+        let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+        let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+        for pages in 1..4 {
+            let mut vd = VecDeque::new();
+            for _ in 0..pages {
+                let mut buffer = alloc.allocate();
+                for i in 0..buffer.remaining() {
+                    buffer[i] = (i % 16) as u8;
+                }
+                vd.push_back(buffer);
+            }
+
+            let compressed = deflate
+                .process(cursor::Multibytes::new(vd), &alloc)
+                .expect("could not deflate");
+
+            // The output fills its pages exactly, so not one more should be allocated
+            let before = alloc.allocations();
+            let reinflated = inflate
+                .process(compressed, &alloc)
+                .expect("could not inflate");
+            assert_eq!(alloc.allocations() - before, pages);
+            assert_eq!(reinflated.page_count(), pages);
+            let mut v = reinflated.view();
+            for i in 0..256 * pages {
+                assert_eq!(v.get_u8(), (i % 16) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn bidirectional_no_leaks() {
+        let alloc = mempool::CountingMemPool::new(8);
+
+        {
+            let mut deflate = MbZlibOp::deflate(5).expect("could not init deflate");
+            let mut inflate = MbZlibOp::inflate().expect("could not init inflate");
+
+            let mut buffer = alloc.allocate();
+            for i in 0..buffer.remaining() {
+                buffer[i] = (i % 16) as u8;
+            }
+
+            let mb = cursor::Multibytes::from_buf(buffer);
+
+            let compressed = deflate.process(mb, &alloc).expect("could not deflate");
+            let reinflated = inflate
+                .process(compressed, &alloc)
+                .expect("could not inflate");
+            let mut v = reinflated.view();
+            for i in 0..=255 {
+                assert_eq!(i % 16 as u8, v.get_u8());
+            }
+            assert!(alloc.outstanding() > 0);
+        }
+
+        assert!(alloc.allocations() > 0);
+        assert_eq!(alloc.allocations(), alloc.frees());
+    }
+
     extern crate test;
     use test::Bencher;
     global_mempool_tlmp!(bench_deflate_inflate_cycle_tlmp, 16);
@@ -170,6 +1335,7 @@ pub mod tests {
                 buf_size: 8,
                 page_entries: 128,
                 concurrent_allocation_limit: 1,
+                ..Default::default()
             },
         );
 
@@ -181,9 +1347,7 @@ pub mod tests {
             buffer[i] = (i % 16) as u8;
         }
 
-        let mut vd = VecDeque::new();
-        vd.push_back(buffer);
-        let mut mb = Some(cursor::Multibytes::new(vd));
+        let mut mb = Some(cursor::Multibytes::from_buf(buffer));
         // There has to be a better way to do this...
         b.iter(|| {
             for _i in 0..1000 {
@@ -46,6 +46,73 @@ impl Cryptor {
     pub fn start_crypto(&mut self, key: [u8; 16]) {
         self.c = Some(AesCryptCfb8::new(key));
     }
+
+    pub fn mode(&self) -> CryptMode {
+        self.mode
+    }
+
+    /// Switches which direction the data after this is processed in. The CFB8 feedback register
+    /// is the last 16 bytes of ciphertext whichever the direction, so it carries straight over -
+    /// after switching, data is treated as continuing the same stream of ciphertext as before.
+    pub fn set_mode(&mut self, mode: CryptMode) {
+        self.mode = mode;
+    }
+}
+
+/// Carries a Cryptor across data which arrives a little at a time. CFB8 works a byte at a time,
+/// and the feedback register lives on in the Cryptor between calls, so any split of the data comes
+/// out the same as processing it all at once. This also keeps track of how much of the buffer
+/// currently being filled has already been processed, for buffers which are read into in pieces.
+pub struct CryptorStream {
+    c: Cryptor,
+    offset: usize,
+    processed: u64,
+}
+
+impl CryptorStream {
+    pub fn new(c: Cryptor) -> CryptorStream {
+        CryptorStream {
+            c,
+            offset: 0,
+            processed: 0,
+        }
+    }
+
+    /// Processes all of data, of any length
+    pub fn process(&mut self, data: &mut [u8]) {
+        self.c.process(data);
+        self.processed += data.len() as u64;
+    }
+
+    /// Processes whatever has been added to buf since the last call, for a buffer which is only
+    /// ever appended to. Call next_buffer before moving on to another buffer.
+    pub fn process_tail(&mut self, buf: &mut [u8]) {
+        assert!(
+            buf.len() >= self.offset,
+            "buffer shrank while being processed"
+        );
+        let offset = self.offset;
+        self.process(&mut buf[offset..]);
+        self.offset = buf.len();
+    }
+
+    /// Starts process_tail over at the beginning of a fresh buffer
+    pub fn next_buffer(&mut self) {
+        self.offset = 0;
+    }
+
+    /// Total bytes processed, including any before crypto was started
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    pub fn start_crypto(&mut self, key: [u8; 16]) {
+        self.c.start_crypto(key)
+    }
+
+    pub fn into_inner(self) -> Cryptor {
+        self.c
+    }
 }
 
 #[cfg(test)]
@@ -76,6 +143,31 @@ mod tests {
         assert_eq!(msg, [0, 1, 2, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn switch_mode() {
+        let key: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+        let mut c = Cryptor::new_decrypt();
+        c.set_mode(CryptMode::Encrypt);
+        assert_eq!(c.mode(), CryptMode::Encrypt);
+        c.start_crypto(key);
+        let mut msg: [u8; 7] = [0, 1, 2, 3, 4, 5, 6];
+        c.process(&mut msg);
+        assert_eq!(msg, [0x0a, 0x22, 0xf7, 0x96, 0xe1, 0xb9, 0x3e]);
+
+        // Decrypting carries on from the ciphertext so far, the same as a decryptor which had seen
+        // all of it would
+        let mut reference = Cryptor::new_decrypt();
+        reference.start_crypto(key);
+        let mut all = [0x0a, 0x22, 0xf7, 0x96, 0xe1, 0xb9, 0x3e, 0x55, 0x66];
+        reference.process(&mut all);
+
+        c.set_mode(CryptMode::Decrypt);
+        let mut tail = [0x55, 0x66];
+        c.process(&mut tail);
+        assert_eq!(tail, all[7..]);
+    }
+
     #[test]
     fn passthrough() {
         let mut msg: [u8; 7] = [0, 1, 2, 3, 4, 5, 6];
@@ -85,4 +177,47 @@ mod tests {
 
         assert_eq!(msg, [0, 1, 2, 3, 4, 5, 6]);
     }
+
+    #[test]
+    fn stream_split() {
+        let key: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let plain: Vec<u8> = (0..100).map(|i| (i * 7) as u8).collect();
+
+        let mut whole = plain.clone();
+        let mut c = Cryptor::new_encrypt();
+        c.start_crypto(key);
+        c.process(&mut whole);
+
+        // One byte at a time
+        let mut bytewise = plain.clone();
+        let mut s = CryptorStream::new(Cryptor::new_encrypt());
+        s.start_crypto(key);
+        for b in bytewise.chunks_mut(1) {
+            s.process(b);
+        }
+        assert_eq!(bytewise, whole);
+        assert_eq!(s.processed(), 100);
+
+        // A buffer which grows by uneven amounts, then a second buffer
+        let mut s = CryptorStream::new(Cryptor::new_encrypt());
+        s.start_crypto(key);
+        let mut buf = Vec::new();
+        for chunk in plain[..60].chunks(13) {
+            buf.extend_from_slice(chunk);
+            s.process_tail(&mut buf);
+        }
+        s.next_buffer();
+        let mut second = plain[60..].to_vec();
+        s.process_tail(&mut second[..1]);
+        s.process_tail(&mut second);
+        buf.extend_from_slice(&second);
+        assert_eq!(buf, whole);
+
+        let mut d = CryptorStream::new(Cryptor::new_decrypt());
+        d.start_crypto(key);
+        for b in buf.chunks_mut(3) {
+            d.process(b);
+        }
+        assert_eq!(buf, plain);
+    }
 }
@@ -22,6 +22,24 @@ use std::io::IoSlice;
 pub trait DirectBuf: bytes::Buf + std::convert::AsRef<[u8]> {
     fn split_to(&mut self, at: usize) -> Self;
     fn truncate(&mut self, len: usize);
+
+    /// Where the allocation backing this buffer starts. Buffers split from one another report the
+    /// same base pointer for as long as they share that allocation. Types which don't let on where
+    /// their allocation starts report None.
+    fn base_ptr(&self) -> Option<*const u8>;
+
+    /// Truncates to len bytes, unless that is longer than the buffer already is, in which case
+    /// the buffer is left alone and its length is returned. Unlike truncate, this behaves the same
+    /// for every implementation.
+    fn try_truncate(&mut self, len: usize) -> Result<(), usize> {
+        let cur = self.remaining();
+        if len > cur {
+            Err(cur)
+        } else {
+            self.truncate(len);
+            Ok(())
+        }
+    }
 }
 
 impl DirectBuf for Bytes {
@@ -32,6 +50,11 @@ impl DirectBuf for Bytes {
     fn split_to(&mut self, at: usize) -> Self {
         self.split_to(at)
     }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        // Bytes keeps where its allocation starts to itself
+        None
+    }
 }
 
 pub trait DirectBufMut: bytes::BufMut + DirectBuf + std::convert::AsMut<[u8]> {
@@ -46,6 +69,11 @@ impl DirectBuf for BytesMut {
     fn split_to(&mut self, at: usize) -> Self {
         self.split_to(at)
     }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        // As does BytesMut
+        None
+    }
 }
 
 impl DirectBufMut for BytesMut {
@@ -55,10 +83,159 @@ impl DirectBufMut for BytesMut {
     }
 }
 
+/// A Vec<u8> which can be used as a DirectBuf. Reading from it moves a position forward through
+/// the Vec rather than shifting its contents, and writing to it appends like a Vec would.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VecBuf {
+    v: Vec<u8>,
+    pos: usize,
+}
+
+impl VecBuf {
+    pub fn new() -> VecBuf {
+        VecBuf {
+            v: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn with_capacity(cap: usize) -> VecBuf {
+        VecBuf {
+            v: Vec::with_capacity(cap),
+            pos: 0,
+        }
+    }
+
+    /// Returns the unread contents of this buffer
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.v.drain(..self.pos);
+        self.v
+    }
+}
+
+impl From<Vec<u8>> for VecBuf {
+    fn from(v: Vec<u8>) -> VecBuf {
+        VecBuf { v, pos: 0 }
+    }
+}
+
+impl std::ops::Deref for VecBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.v[self.pos..]
+    }
+}
+
+impl std::ops::DerefMut for VecBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.v[self.pos..]
+    }
+}
+
+impl AsRef<[u8]> for VecBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for VecBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Buf for VecBuf {
+    fn remaining(&self) -> usize {
+        self.v.len() - self.pos
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        if cnt > self.remaining() {
+            panic!("advance past end of VecBuf");
+        }
+        self.pos += cnt;
+    }
+}
+
+impl BufMut for VecBuf {
+    fn remaining_mut(&self) -> usize {
+        self.v.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.v.advance_mut(cnt)
+    }
+
+    fn bytes_mut(&mut self) -> &mut [std::mem::MaybeUninit<u8>] {
+        self.v.bytes_mut()
+    }
+}
+
+impl DirectBuf for VecBuf {
+    fn truncate(&mut self, len: usize) {
+        self.v.truncate(self.pos + len)
+    }
+
+    fn split_to(&mut self, at: usize) -> Self {
+        // The front keeps the original allocation, so only the back half gets copied
+        let back = self.v.split_off(self.pos + at);
+        let front = std::mem::replace(&mut self.v, back);
+        let pos = std::mem::replace(&mut self.pos, 0);
+        VecBuf { v: front, pos }
+    }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        Some(self.v.as_ptr())
+    }
+}
+
+impl DirectBufMut for VecBuf {
+    unsafe fn bytes_mut_assume_init(&mut self) -> &mut [u8] {
+        std::mem::transmute(self.v.bytes_mut())
+    }
+}
+
 pub trait SliceCursor: bytes::Buf {
     fn has_atleast(&self, len: usize) -> bool {
         self.remaining() >= len
     }
+
+    /// Takes everything up to and including the first delim, for formats which are delimited
+    /// rather than length prefixed. If delim isn't within the first max bytes, nothing is consumed
+    /// and None is returned - more data may yet bring it within reach.
+    fn read_until(&mut self, delim: u8, max: usize) -> Option<Vec<u8>> {
+        let len = {
+            // Look ahead over every page, growing the slices until they hold all of them
+            let mut slices = vec![IoSlice::new(&[]); 8];
+            let mut n = self.bytes_vectored(&mut slices);
+            while n == slices.len() {
+                slices.resize(n * 2, IoSlice::new(&[]));
+                n = self.bytes_vectored(&mut slices);
+            }
+
+            let mut seen = 0;
+            let mut found = None;
+            for s in &slices[..n] {
+                if let Some(i) = s.iter().take(max - seen).position(|&b| b == delim) {
+                    found = Some(seen + i + 1);
+                    break;
+                }
+                seen += s.len();
+                if seen >= max {
+                    break;
+                }
+            }
+            found?
+        };
+
+        let mut out = vec![0; len];
+        self.copy_to_slice(&mut out);
+        Some(out)
+    }
 }
 
 impl SliceCursor for Bytes {}
@@ -67,6 +244,8 @@ pub trait SliceCursorMut: BufMut + SliceCursor {}
 
 impl SliceCursor for BytesMut {}
 
+impl SliceCursor for VecBuf {}
+
 #[derive(Debug)]
 pub struct Multibytes<T: DirectBuf> {
     pub(crate) b: VecDeque<T>,
@@ -148,18 +327,18 @@ impl Cursor {
 
         dst[0] = IoSlice::new(&first.as_ref()[self.i..]);
 
-        // Others can just be slammed in there, no problems
+        // Others can just be slammed in there, so long as there is room
         let mut i = 1;
-        while let Some(item) = iter.next() {
+        for item in iter {
+            if i >= dstlen {
+                break;
+            }
             if item.remaining() == 0 {
                 continue;
             }
 
             dst[i] = IoSlice::new(&item.as_ref()[..]);
             i += 1;
-            if i >= dstlen {
-                break;
-            }
         }
         return i;
     }
@@ -184,6 +363,15 @@ impl Cursor {
             }
         }
     }
+
+    /// Pulls a cursor which has run off the end of b back to the very end of it, so that it can be
+    /// used to split there. A cursor which hasn't run off is left alone.
+    pub fn clamp_to_end<T: DirectBuf>(&mut self, b: &Multibytes<T>) {
+        if self.run_off_end(b) > 0 {
+            self.of = b.b.len();
+            self.i = 0;
+        }
+    }
 }
 
 macro_rules! must_be_some {
@@ -200,6 +388,13 @@ impl<T: DirectBuf> Multibytes<T> {
         Multibytes { b }
     }
 
+    /// A Multibytes with just the one page
+    pub fn from_buf(b: T) -> Multibytes<T> {
+        let mut vd = VecDeque::with_capacity(1);
+        vd.push_back(b);
+        Multibytes { b: vd }
+    }
+
     pub fn cursor(&self) -> Cursor {
         Cursor { of: 0, i: 0 }
     }
@@ -208,6 +403,67 @@ impl<T: DirectBuf> Multibytes<T> {
         self.b.push_back(b)
     }
 
+    /// Puts all of the pages of front ahead of the pages in this Multibytes. Any Cursor into this
+    /// Multibytes should be considered invalid afterwards.
+    pub fn prepend(&mut self, front: Multibytes<T>) {
+        for p in front.b.into_iter().rev() {
+            self.b.push_front(p);
+        }
+    }
+
+    /// The number of pages backing this Multibytes, including empty ones
+    pub fn page_count(&self) -> usize {
+        self.b.len()
+    }
+
+    /// The length of the longest page, or 0 if there are none
+    pub fn max_page_len(&self) -> usize {
+        self.b.iter().map(|p| p.remaining()).max().unwrap_or(0)
+    }
+
+    /// The length of the shortest page, counting empty ones, or 0 if there are none
+    pub fn min_page_len(&self) -> usize {
+        self.b.iter().map(|p| p.remaining()).min().unwrap_or(0)
+    }
+
+    /// Drops all pages held by this Multibytes
+    pub fn clear(&mut self) {
+        self.b.clear()
+    }
+
+    /// Removes all pages which have no data left in them. This shifts pages around, so any Cursor
+    /// into this Multibytes should be considered invalid afterwards.
+    pub fn trim_empty(&mut self) {
+        self.b.retain(|p| p.remaining() > 0)
+    }
+
+    /// True if there are no bytes left in any page of this Multibytes
+    pub fn is_empty(&self) -> bool {
+        self.b.iter().all(|p| p.remaining() == 0)
+    }
+
+    /// Whether any page of this Multibytes is backed by the same allocation as a page of other,
+    /// going by DirectBuf::base_ptr. If so, writing into one of them in place could change the
+    /// other. None if that can't be known because some page can't tell where its allocation
+    /// starts, and no other pair of pages was found to share one.
+    pub fn shares_storage_with(&self, other: &Multibytes<T>) -> Option<bool> {
+        let mut known = true;
+        for p in self.b.iter() {
+            for o in other.b.iter() {
+                match (p.base_ptr(), o.base_ptr()) {
+                    (Some(a), Some(b)) if a == b => return Some(true),
+                    (Some(_), Some(_)) => {}
+                    _ => known = false,
+                }
+            }
+        }
+        if known {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
     /// Before using this method, a Cursor should be 'trued up'
     pub fn split_to(&mut self, c: &Cursor) -> Self {
         // If our index into a buffer is 0, then we don't actually have to split it. We just have
@@ -247,6 +503,13 @@ impl<T: DirectBuf> Multibytes<T> {
         return Multibytes { b };
     }
 
+    /// The inverse of split_to - everything before the cursor is kept, and everything after it is
+    /// returned. Like split_to, the cursor should be 'trued up' before use.
+    pub fn split_off(&mut self, c: &Cursor) -> Self {
+        let front = self.split_to(c);
+        std::mem::replace(self, front)
+    }
+
     pub fn view<'a>(&'a self) -> MultibytesView<'a, T> {
         MultibytesView {
             b: self,
@@ -270,6 +533,75 @@ impl<T: DirectBuf> Multibytes<T> {
     }
 }
 
+/// Consumes the Multibytes into its pages, front to back
+impl<T: DirectBuf> IntoIterator for Multibytes<T> {
+    type Item = T;
+    type IntoIter = std::collections::vec_deque::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.b.into_iter()
+    }
+}
+
+impl<T: DirectBuf> From<Multibytes<T>> for VecDeque<T> {
+    fn from(mb: Multibytes<T>) -> VecDeque<T> {
+        mb.b
+    }
+}
+
+impl Multibytes<Bytes> {
+    /// Copies the slice into a single page
+    pub fn from_slice(s: &[u8]) -> Multibytes<Bytes> {
+        Multibytes::from_buf(Bytes::copy_from_slice(s))
+    }
+}
+
+impl<T: DirectBufMut> Multibytes<T> {
+    /// Hands f every byte from c onwards to change in place, a page at a time
+    pub fn for_each_mut_from<F: FnMut(&mut [u8])>(&mut self, c: &Cursor, mut f: F) {
+        let mut skip = c.i;
+        for page in self.b.iter_mut().skip(c.of) {
+            let data = page.as_mut();
+            if skip < data.len() {
+                f(&mut data[skip..]);
+            }
+            skip = skip.saturating_sub(data.len());
+        }
+    }
+}
+
+/// Reading from a Multibytes through Buf consumes it - pages are dropped from the front as they are
+/// advanced past. This allows a Multibytes to be drained in place, e.g. by write_buf.
+impl<T: DirectBuf> Buf for Multibytes<T> {
+    fn remaining(&self) -> usize {
+        self.cursor().remaining(self)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.b.iter().find(|p| p.remaining() > 0) {
+            Some(x) => x.bytes(),
+            None => &[],
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        let mut c = self.cursor();
+        c.advance(self, cnt);
+        // Everything before the cursor has been consumed, so just drop it
+        self.split_to(&c);
+    }
+
+    fn bytes_vectored<'b>(&'b self, dst: &mut [IoSlice<'b>]) -> usize {
+        self.cursor().bytes_vectored(self, dst)
+    }
+}
+
+impl<T: DirectBuf> SliceCursor for Multibytes<T> {
+    fn has_atleast(&self, len: usize) -> bool {
+        self.cursor().has_atleast(self, len)
+    }
+}
+
 pub struct IndexedMultibytes<T: DirectBuf> {
     b: Multibytes<T>,
     c: Cursor,
@@ -310,6 +642,76 @@ impl<T: DirectBuf> IndexedMultibytes<T> {
     pub fn dissolve(self) -> (Multibytes<T>, Cursor) {
         (self.b, self.c)
     }
+
+    /// Rewinds back to the start of the first page, so that everything can be read again
+    pub fn reset_cursor(&mut self) {
+        self.c = Cursor { of: 0, i: 0 };
+        self.c.true_up(&self.b);
+    }
+
+    /// The cursor reads are made from, for putting back a position saved from cursor earlier
+    pub fn cursor_mut(&mut self) -> &mut Cursor {
+        &mut self.c
+    }
+
+    /// Fills the scratch space with the remaining data of this IndexedMultibytes, ready to be
+    /// handed to write_vectored.
+    pub fn io_slices<'a>(&'a self, scratch: &'a mut IoSliceScratch) -> &'a [IoSlice<'a>] {
+        scratch.fill(&self.c, &self.b)
+    }
+}
+
+impl<T: DirectBuf + Clone> IndexedMultibytes<T> {
+    /// Clones everything after the cursor into a new IndexedMultibytes of its own, leaving this one
+    /// untouched. Only the pages which still have data left are cloned, which for refcounted
+    /// buffers like Bytes is cheap.
+    pub fn clone_remaining(&self) -> IndexedMultibytes<T> {
+        let mut b = VecDeque::with_capacity(self.b.b.len().saturating_sub(self.c.of));
+        for (n, page) in self.b.b.iter().enumerate().skip(self.c.of) {
+            let mut page = page.clone();
+            if n == self.c.of {
+                page.advance(self.c.i);
+            }
+            b.push_back(page);
+        }
+        Multibytes::new(b).indexed()
+    }
+}
+
+/// Reusable backing space for IoSlices, so that vectored writes don't have to allocate a new slice
+/// array for every write.
+pub struct IoSliceScratch {
+    // The lifetime here is a lie - the vec is always cleared before it is handed out with a real
+    // lifetime attached, so no IoSlice outlives the buffer it points into.
+    v: Vec<IoSlice<'static>>,
+}
+
+impl IoSliceScratch {
+    pub fn new() -> IoSliceScratch {
+        IoSliceScratch { v: Vec::new() }
+    }
+
+    pub fn with_capacity(cap: usize) -> IoSliceScratch {
+        IoSliceScratch {
+            v: Vec::with_capacity(cap),
+        }
+    }
+
+    /// Fills the scratch space with the data after the cursor
+    pub fn fill<'a, T: DirectBuf>(
+        &'a mut self,
+        c: &Cursor,
+        mb: &'a Multibytes<T>,
+    ) -> &'a [IoSlice<'a>] {
+        self.v.clear();
+        // SAFETY: the vec is empty, and the slices put into it can't outlive 'a as the vec stays
+        // mutably borrowed for all of 'a.
+        let v: &'a mut Vec<IoSlice<'a>> = unsafe { std::mem::transmute(&mut self.v) };
+
+        v.resize(mb.page_count().saturating_sub(c.of), IoSlice::new(&[]));
+        let n = c.bytes_vectored(mb, v);
+        &v[..n]
+    }
 }
 
 pub struct MultibytesView<'a, T: DirectBuf> {
@@ -357,6 +759,33 @@ impl<'a, T: DirectBuf> MultibytesView<'a, T> {
     pub fn cursor(&self) -> Cursor {
         self.c
     }
+
+    /// The longest run of bytes which can be read in place from the cursor, which is the rest of the
+    /// page it is on. This may well be shorter than remaining, as the view can carry on into later
+    /// pages - it is only empty once there is nothing left at all.
+    pub fn contiguous(&self) -> &[u8] {
+        self.bytes()
+    }
+
+    /// The length of contiguous
+    pub fn page_remaining(&self) -> usize {
+        self.contiguous().len()
+    }
+
+    /// True if what is left of the view is exactly other, compared page by page in place
+    pub fn content_eq(&self, other: &[u8]) -> bool {
+        let mut rest = other;
+        let mut skip = self.c.i;
+        for page in self.b.b.iter().skip(self.c.of) {
+            let page = &page.as_ref()[std::cmp::min(skip, page.remaining())..];
+            skip = 0;
+            if page.len() > rest.len() || page != &rest[..page.len()] {
+                return false;
+            }
+            rest = &rest[page.len()..];
+        }
+        rest.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -378,7 +807,143 @@ mod tests {
     use super::*;
     use std::iter::FromIterator;
 
-    fn make_test_mb() -> Multibytes<bytes::Bytes> {
+    #[test]
+    fn vecbuf_split_to() {
+        let mut v = VecBuf::from(vec![1, 2, 3, 4, 5, 6]);
+        v.advance(1);
+        let front = v.split_to(2);
+        assert_eq!(front.bytes(), [2, 3]);
+        assert_eq!(v.bytes(), [4, 5, 6]);
+
+        v.truncate(2);
+        v.put_u8(9);
+        assert_eq!(v.into_vec(), vec![4, 5, 9]);
+    }
+
+    #[test]
+    fn multibytes_from_slice() {
+        let mb = Multibytes::from_slice(&[1, 2, 3]);
+        assert_eq!(mb.page_count(), 1);
+        assert_eq!(mb_to_vec(&mb), vec![1, 2, 3]);
+
+        let mb = Multibytes::from_buf(VecBuf::from(vec![4, 5]));
+        assert_eq!(mb.page_count(), 1);
+        assert_eq!(mb_to_vec(&mb), vec![4, 5]);
+    }
+
+    #[test]
+    fn indexed_clone_remaining() {
+        let mut indexed = make_test_mb::<Bytes>().indexed();
+        indexed.advance(5);
+
+        let mut clone = indexed.clone_remaining();
+        assert_eq!(clone.remaining(), 5);
+        assert_eq!(clone.get_u8(), 6);
+
+        // Each reads on without disturbing the other
+        indexed.advance(2);
+        assert_eq!(indexed.get_u8(), 8);
+        let rest: Vec<u8> = (0..4).map(|_| clone.get_u8()).collect();
+        assert_eq!(rest, vec![7, 8, 9, 10]);
+        assert!(!clone.has_remaining());
+        assert_eq!(indexed.remaining(), 2);
+
+        let mut empty = make_test_mb::<Bytes>().indexed();
+        empty.advance(10);
+        assert_eq!(empty.clone_remaining().remaining(), 0);
+    }
+
+    #[test]
+    fn multibytes_into_pages() {
+        let pages: Vec<Bytes> = make_test_mb::<Bytes>().into_iter().collect();
+        assert_eq!(pages.len(), 5);
+        assert_eq!(&pages[0][..], [1, 2, 3, 4]);
+        assert_eq!(&pages[2][..], []);
+        assert_eq!(&pages[4][..], [10]);
+
+        let vd: VecDeque<Bytes> = make_test_mb::<Bytes>().into();
+        assert_eq!(vd.len(), 5);
+        assert_eq!(&vd[3][..], [7, 8, 9]);
+    }
+
+    fn check_try_truncate<T: DirectBuf>(mut b: T) {
+        let len = b.remaining();
+        assert_eq!(b.try_truncate(len + 1), Err(len));
+        assert_eq!(b.remaining(), len);
+        assert_eq!(b.try_truncate(len - 1), Ok(()));
+        assert_eq!(b.remaining(), len - 1);
+    }
+
+    global_mempool_tlmp!(try_truncate_pool, 4);
+    #[test]
+    fn try_truncate() {
+        use crate::mempool::{self, BlockAllocator};
+
+        check_try_truncate(Bytes::from_static(&[1, 2, 3]));
+        check_try_truncate(BytesMut::from(&[1, 2, 3][..]));
+        check_try_truncate(VecBuf::from(vec![1, 2, 3]));
+
+        let pool = mempool::GlobalMemPool::new(
+            &try_truncate_pool,
+            mempool::GlobalMemPoolSettings {
+                buf_size: 12,
+                page_entries: 4,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        );
+        check_try_truncate(pool.allocate());
+
+        let counting = mempool::CountingMemPool::new(4);
+        check_try_truncate(counting.allocate());
+    }
+
+    global_mempool_tlmp!(shares_storage_pool, 4);
+    #[test]
+    fn shares_storage() {
+        use crate::mempool::{self, BlockAllocator};
+
+        let pool = mempool::GlobalMemPool::new(
+            &shares_storage_pool,
+            mempool::GlobalMemPoolSettings {
+                buf_size: 12,
+                page_entries: 4,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        );
+
+        let mut parent = Multibytes::from_buf(pool.allocate());
+        let mut c = parent.cursor();
+        c.advance(&parent, 100);
+        let child = parent.split_to(&c);
+        assert_eq!(parent.shares_storage_with(&child), Some(true));
+        assert_eq!(child.shares_storage_with(&parent), Some(true));
+
+        let other = Multibytes::from_buf(pool.allocate());
+        assert_eq!(parent.shares_storage_with(&other), Some(false));
+
+        // VecBuf copies when it is split, so the two halves end up apart
+        let mut parent = Multibytes::from_buf(VecBuf::from(vec![1, 2, 3, 4]));
+        let mut c = parent.cursor();
+        c.advance(&parent, 2);
+        let child = parent.split_to(&c);
+        assert_eq!(parent.shares_storage_with(&child), Some(false));
+
+        // Bytes won't say where its allocation starts, so whether its halves are shared is unknown
+        let mut parent = Multibytes::from_buf(Bytes::from(vec![1, 2, 3, 4]));
+        let mut c = parent.cursor();
+        c.advance(&parent, 2);
+        let child = parent.split_to(&c);
+        assert_eq!(parent.shares_storage_with(&child), None);
+        assert_eq!(child.shares_storage_with(&parent), None);
+    }
+
+    // Everything a page type needs for the tests below to be run against it
+    trait TestBuf: DirectBuf + From<Vec<u8>> {}
+    impl<T: DirectBuf + From<Vec<u8>>> TestBuf for T {}
+
+    fn make_test_mb<T: TestBuf>() -> Multibytes<T> {
         let slices = vec![
             vec![1, 2, 3, 4],
             vec![5, 6],
@@ -387,17 +952,21 @@ mod tests {
             vec![10],
         ];
         Multibytes {
-            b: VecDeque::from_iter(
-                slices
-                    .iter()
-                    .map(|s| bytes::BytesMut::from_iter(s.iter()).freeze()),
-            ),
+            b: VecDeque::from_iter(slices.into_iter().map(T::from)),
         }
     }
 
-    #[test]
-    fn cursor_advance() {
-        let mb = make_test_mb();
+    fn mb_to_vec<T: DirectBuf>(mb: &Multibytes<T>) -> Vec<u8> {
+        let mut v = mb.view();
+        let mut out = Vec::new();
+        while v.has_remaining() {
+            out.push(v.get_u8());
+        }
+        out
+    }
+
+    fn cursor_advance<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         assert!(cursor.advance(&mb, 3));
@@ -405,9 +974,8 @@ mod tests {
         assert!(!cursor.advance(&mb, 1));
     }
 
-    #[test]
-    fn cursor_remaining() {
-        let mb = make_test_mb();
+    fn cursor_remaining<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         assert_eq!(cursor.remaining(&mb), 10);
@@ -419,9 +987,8 @@ mod tests {
         assert_eq!(cursor.remaining(&mb), 0);
     }
 
-    #[test]
-    fn cursor_has_atleast() {
-        let mb = make_test_mb();
+    fn cursor_has_atleast<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         assert!(cursor.has_atleast(&mb, 0));
@@ -439,9 +1006,8 @@ mod tests {
         assert!(!cursor.has_atleast(&mb, 0));
     }
 
-    #[test]
-    fn cursor_bytes_vectored() {
-        let mb = make_test_mb();
+    fn cursor_bytes_vectored<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         let mut io = vec![
@@ -454,6 +1020,11 @@ mod tests {
         // by definition we must return 0
         assert_eq!(cursor.bytes_vectored(&mb, &mut []), 0);
 
+        // Only one slot to fill, with plenty more pages behind it
+        let mut one = [IoSlice::new(&[])];
+        assert_eq!(cursor.bytes_vectored(&mb, &mut one), 1);
+        assert_eq!(one[0].to_vec(), vec![1, 2, 3, 4]);
+
         assert_eq!(cursor.bytes_vectored(&mb, &mut io), 4);
         assert_eq!(io[0].to_vec(), vec![1, 2, 3, 4]);
         assert_eq!(io[1].to_vec(), vec![5, 6]);
@@ -485,9 +1056,8 @@ mod tests {
         assert_eq!(cursor.bytes_vectored(&mb, &mut []), 0);
     }
 
-    #[test]
-    fn cursor_run_off_end() {
-        let mut mb = make_test_mb();
+    fn cursor_run_off_end<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         for _ in 0..10 {
@@ -497,15 +1067,35 @@ mod tests {
 
         cursor.advance(&mb, 1);
         assert_eq!(cursor.run_off_end(&mb), 1);
-        mb.append(bytes::BytesMut::from_iter(vec![11, 12].iter()).freeze());
+        mb.append(T::from(vec![11, 12]));
         assert_eq!(cursor.run_off_end(&mb), 0);
         cursor.advance(&mb, 101);
         assert_eq!(cursor.run_off_end(&mb), 100);
     }
 
-    #[test]
-    fn multibytes_split_to() {
-        let mut mb = make_test_mb();
+    fn cursor_clamp_to_end<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
+        let mut cursor = mb.cursor();
+
+        // Not run off, so nothing should change
+        cursor.advance(&mb, 4);
+        let before = cursor;
+        cursor.clamp_to_end(&mb);
+        assert_eq!(cursor, before);
+
+        cursor.advance(&mb, 20);
+        assert_eq!(cursor.run_off_end(&mb), 14);
+        cursor.clamp_to_end(&mb);
+        assert_eq!(cursor.run_off_end(&mb), 0);
+        assert_eq!(cursor.remaining(&mb), 0);
+
+        let front = mb.split_to(&cursor);
+        assert_eq!(front.remaining(), 10);
+        assert_eq!(mb.remaining(), 0);
+    }
+
+    fn multibytes_split_to<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
         let mut cursor = mb.cursor();
 
         let mb_empty = mb.split_to(&cursor);
@@ -555,4 +1145,323 @@ mod tests {
         // run with ASAN / valgrind to ensure bytes didn't mess up
         drop(mb_4);
     }
+
+    fn multibytes_split_off<T: TestBuf>() {
+        let original = mb_to_vec(&make_test_mb::<T>());
+
+        for at in 0..=original.len() {
+            let mut mb = make_test_mb::<T>();
+            let mut cursor = mb.cursor();
+            cursor.advance(&mb, at);
+
+            let tail = mb.split_off(&cursor);
+            assert_eq!(mb_to_vec(&mb), original[..at].to_vec());
+            assert_eq!(mb_to_vec(&tail), original[at..].to_vec());
+        }
+    }
+
+    fn multibytes_clear<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
+        assert!(!mb.is_empty());
+
+        mb.clear();
+        assert!(mb.is_empty());
+        assert_eq!(mb.b.len(), 0);
+        assert_eq!(mb.cursor().remaining(&mb), 0);
+
+        mb.append(T::from(vec![]));
+        assert!(mb.is_empty());
+    }
+
+    fn multibytes_trim_empty<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
+        let original = mb_to_vec(&mb);
+        assert_eq!(mb.page_count(), 5);
+
+        mb.trim_empty();
+        assert_eq!(mb.page_count(), 4);
+        assert!(mb.b.iter().all(|p| p.remaining() > 0));
+        assert_eq!(mb_to_vec(&mb), original);
+    }
+
+    fn multibytes_page_lens<T: TestBuf>() {
+        let mut mb = make_test_mb::<T>();
+        assert_eq!(mb.max_page_len(), 4);
+        assert_eq!(mb.min_page_len(), 0);
+
+        mb.trim_empty();
+        assert_eq!(mb.min_page_len(), 1);
+
+        mb.clear();
+        assert_eq!(mb.max_page_len(), 0);
+        assert_eq!(mb.min_page_len(), 0);
+    }
+
+    fn multibytes_buf_drain<T: TestBuf>() {
+        // Mirrors the way write_buf pulls from whatever Buf it is handed
+        fn drain<B: Buf>(mut buf: B) -> Vec<u8> {
+            let mut out = Vec::new();
+            while buf.has_remaining() {
+                let chunk = buf.bytes();
+                // Take less than a whole chunk to exercise partial page advances
+                let n = std::cmp::min(chunk.len(), 3);
+                out.extend_from_slice(&chunk[..n]);
+                buf.advance(n);
+            }
+            out
+        }
+
+        let mut mb = make_test_mb::<T>();
+        let original = mb_to_vec(&mb);
+        assert_eq!(mb.remaining(), original.len());
+
+        assert_eq!(drain(&mut mb), original);
+        assert!(mb.is_empty());
+        assert_eq!(mb.remaining(), 0);
+        assert_eq!(mb.bytes(), []);
+    }
+
+    fn slice_cursor_read_until<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
+        let mut view = mb.view();
+
+        // The 7 is on another page, past the empty one
+        assert_eq!(view.read_until(7, 7), Some(vec![1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(view.remaining(), 3);
+
+        // Out of reach, or not there at all, leaves everything in place
+        assert_eq!(view.read_until(10, 2), None);
+        assert_eq!(view.read_until(0, 10), None);
+        assert_eq!(view.remaining(), 3);
+
+        assert_eq!(view.read_until(10, 3), Some(vec![8, 9, 10]));
+        assert_eq!(view.remaining(), 0);
+
+        // A delimited string, split over two pages
+        let mut mb = Multibytes::from_buf(T::from(b"MC|Pi".to_vec()));
+        mb.append(T::from(b"ngHost\0\0rest".to_vec()));
+        let mut indexed = mb.indexed();
+        assert_eq!(indexed.read_until(0, 64), Some(b"MC|PingHost\0".to_vec()));
+        assert_eq!(indexed.remaining(), 5);
+    }
+
+    fn view_content_eq<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
+        let mut view = mb.view();
+        assert!(view.content_eq(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
+        assert!(!view.content_eq(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 11]));
+        assert!(!view.content_eq(&[1, 2, 3, 4, 5, 6, 7, 8, 9]));
+        assert!(!view.content_eq(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]));
+
+        // Partway into a page
+        view.advance(5);
+        assert!(view.content_eq(&[6, 7, 8, 9, 10]));
+        assert!(!view.content_eq(&[5, 6, 7, 8, 9, 10]));
+
+        view.advance(5);
+        assert!(view.content_eq(&[]));
+        assert!(!view.content_eq(&[10]));
+    }
+
+    fn view_contiguous<T: TestBuf>() {
+        let mb = make_test_mb::<T>();
+        let mut view = mb.view();
+        assert_eq!(view.contiguous(), &[1, 2, 3, 4]);
+        assert_eq!(view.page_remaining(), 4);
+
+        view.advance(2);
+        assert_eq!(view.contiguous(), &[3, 4]);
+        assert_eq!(view.page_remaining(), 2);
+        assert_eq!(view.remaining(), 8);
+
+        // Onto the start of the next page
+        view.advance(2);
+        assert_eq!(view.contiguous(), &[5, 6]);
+
+        view.advance(6);
+        assert_eq!(view.contiguous(), &[]);
+        assert_eq!(view.page_remaining(), 0);
+    }
+
+    fn indexed_rewind<T: TestBuf>() {
+        let mut indexed = make_test_mb::<T>().indexed();
+        indexed.advance(1);
+        let saved = indexed.cursor();
+        assert_eq!(indexed.get_u32(), 0x02030405);
+
+        // Back to where we were, and read it over again
+        *indexed.cursor_mut() = saved;
+        assert_eq!(indexed.get_u32(), 0x02030405);
+        assert_eq!(indexed.remaining(), 5);
+
+        indexed.reset_cursor();
+        assert_eq!(indexed.remaining(), 10);
+        assert_eq!(indexed.get_u8(), 1);
+        assert_eq!(indexed.get_u32(), 0x02030405);
+    }
+
+    fn indexed_io_slices<T: TestBuf>() {
+        let mut scratch = IoSliceScratch::new();
+        let mb = make_test_mb::<T>();
+        let mut c = mb.cursor();
+        c.advance(&mb, 3);
+        let indexed = mb.cursor_indexed(c);
+
+        let io = indexed.io_slices(&mut scratch);
+        assert_eq!(io.len(), 4);
+        assert_eq!(io[0].to_vec(), vec![4]);
+        assert_eq!(io[1].to_vec(), vec![5, 6]);
+        assert_eq!(io[2].to_vec(), vec![7, 8, 9]);
+        assert_eq!(io[3].to_vec(), vec![10]);
+    }
+
+    extern crate test;
+    use std::io::Write;
+    use test::Bencher;
+
+    fn make_small_mbs() -> Vec<Multibytes<bytes::Bytes>> {
+        (0..1000).map(|_| make_test_mb()).collect()
+    }
+
+    #[bench]
+    fn bench_write_vectored_alloc(b: &mut Bencher) {
+        let mbs = make_small_mbs();
+        let mut sink = std::io::sink();
+        b.iter(|| {
+            for mb in mbs.iter() {
+                let mut io = vec![IoSlice::new(&[]); mb.page_count()];
+                let n = mb.cursor().bytes_vectored(mb, &mut io);
+                test::black_box(sink.write_vectored(&io[..n]).unwrap());
+            }
+        })
+    }
+
+    #[bench]
+    fn bench_write_vectored_scratch(b: &mut Bencher) {
+        let mbs = make_small_mbs();
+        let mut sink = std::io::sink();
+        let mut scratch = IoSliceScratch::new();
+        b.iter(|| {
+            for mb in mbs.iter() {
+                let io = scratch.fill(&mb.cursor(), mb);
+                test::black_box(sink.write_vectored(io).unwrap());
+            }
+        })
+    }
+
+    // 256KB of data, as 4KB pages or as one contiguous page
+    const BENCH_BYTES: usize = 256 * 1024;
+    const BENCH_PAGE: usize = 4096;
+
+    fn make_paged_mb(data: &[u8], page: usize) -> Multibytes<bytes::Bytes> {
+        Multibytes::new(
+            data.chunks(page)
+                .map(|c| bytes::Bytes::copy_from_slice(c))
+                .collect(),
+        )
+    }
+
+    fn bench_cursor_advance(b: &mut Bencher, page: usize) {
+        let mb = make_paged_mb(&vec![0; BENCH_BYTES], page);
+        b.iter(|| {
+            let mut c = mb.cursor();
+            while c.advance(&mb, 1) && c.has_atleast(&mb, 1) {}
+            test::black_box(c);
+        })
+    }
+
+    #[bench]
+    fn bench_cursor_advance_paged(b: &mut Bencher) {
+        bench_cursor_advance(b, BENCH_PAGE)
+    }
+
+    #[bench]
+    fn bench_cursor_advance_contiguous(b: &mut Bencher) {
+        bench_cursor_advance(b, BENCH_BYTES)
+    }
+
+    #[bench]
+    fn bench_bytes_vectored_paged(b: &mut Bencher) {
+        let mb = make_paged_mb(&vec![0; BENCH_BYTES], BENCH_PAGE);
+        let mut io = vec![IoSlice::new(&[]); mb.page_count()];
+        b.iter(|| {
+            for _ in 0..100 {
+                test::black_box(mb.cursor().bytes_vectored(&mb, &mut io));
+            }
+        })
+    }
+
+    // Varints of one to three bytes, so that plenty of them straddle the page boundaries
+    fn bench_view_varints(b: &mut Bencher, page: usize) {
+        let mut data = Vec::with_capacity(BENCH_BYTES);
+        let mut v: i32 = 0;
+        while data.len() < BENCH_BYTES - 5 {
+            let mut out = [0; 5];
+            let n = crate::parser::encode_varint(v, &mut &mut out[..]);
+            data.extend_from_slice(&out[..n]);
+            v = (v + 7919) % 2_000_000;
+        }
+        let mb = make_paged_mb(&data, page);
+
+        b.iter(|| {
+            let mut view = mb.view();
+            while view.has_remaining() {
+                let (rest, v) = crate::parser::varint(view).unwrap();
+                test::black_box(v);
+                view = rest;
+            }
+        })
+    }
+
+    #[bench]
+    fn bench_view_varints_paged(b: &mut Bencher) {
+        bench_view_varints(b, BENCH_PAGE)
+    }
+
+    #[bench]
+    fn bench_view_varints_contiguous(b: &mut Bencher) {
+        bench_view_varints(b, BENCH_BYTES)
+    }
+
+    macro_rules! backed_tests {
+        ($($name:ident),*) => {
+            mod bytes_backed {
+                $(
+                    #[test]
+                    fn $name() {
+                        super::$name::<bytes::Bytes>()
+                    }
+                )*
+            }
+
+            mod vec_backed {
+                $(
+                    #[test]
+                    fn $name() {
+                        super::$name::<super::super::VecBuf>()
+                    }
+                )*
+            }
+        };
+    }
+
+    backed_tests!(
+        cursor_advance,
+        cursor_remaining,
+        cursor_has_atleast,
+        cursor_bytes_vectored,
+        cursor_run_off_end,
+        cursor_clamp_to_end,
+        multibytes_split_to,
+        multibytes_split_off,
+        multibytes_clear,
+        multibytes_trim_empty,
+        multibytes_page_lens,
+        multibytes_buf_drain,
+        indexed_rewind,
+        indexed_io_slices,
+        slice_cursor_read_until,
+        view_content_eq,
+        view_contiguous
+    );
 }
@@ -15,40 +15,30 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-
-pub trait DirectBuf: Buf + std::convert::AsRef<[u8]> {
-    fn split_to(&mut self, at: usize) -> Self;
-    fn truncate(&mut self, len: usize);
-}
-
-impl DirectBuf for Bytes {
-    fn truncate(&mut self, len: usize) {
-        self.truncate(len)
-    }
-
-    fn split_to(&mut self, at: usize) -> Self {
-        self.split_to(at)
+// These traits used to be duplicated here, which made a type implementing one unusable wherever the
+// other was expected. cursor holds the canonical definitions.
+pub use super::cursor::{DirectBuf, DirectBufMut};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cursor;
+
+    // Bounded on the direct traits, but handed straight to cursor
+    fn single_page<T: DirectBuf>(b: T) -> cursor::Multibytes<T> {
+        cursor::Multibytes::from_buf(b)
     }
-}
-
-pub trait DirectBufMut: bytes::BufMut + DirectBuf + std::convert::AsMut<[u8]> {
-    unsafe fn bytes_mut_assume_init(&mut self) -> &mut [u8];
-}
 
-impl DirectBuf for BytesMut {
-    fn truncate(&mut self, len: usize) {
-        self.truncate(len)
+    fn split_front<T: DirectBufMut>(mut b: T) -> T {
+        b.split_to(1)
     }
 
-    fn split_to(&mut self, at: usize) -> Self {
-        self.split_to(at)
-    }
-}
+    #[test]
+    fn direct_is_cursor() {
+        let mb = single_page(bytes::Bytes::from_static(&[1, 2, 3]));
+        assert_eq!(mb.cursor().remaining(&mb), 3);
 
-impl DirectBufMut for BytesMut {
-    unsafe fn bytes_mut_assume_init(&mut self) -> &mut [u8] {
-        // look, if you thought this was safe you came to the wrong place
-        std::mem::transmute(self.bytes_mut())
+        let front = split_front(bytes::BytesMut::from(&[1, 2, 3][..]));
+        assert_eq!(&front[..], [1]);
     }
 }
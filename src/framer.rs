@@ -26,21 +26,184 @@ pub struct Frame<T: cursor::DirectBuf> {
     pub data_start: cursor::Cursor,
 }
 
+/// A field which Frame::validate_varints can step over, checking any varints along the way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    VarInt,
+    VarLong,
+    /// A field of a fixed number of bytes, which are skipped over as is
+    Fixed(usize),
+    /// A varint length, followed by that many bytes - strings and byte arrays
+    LengthPrefixed,
+    /// Whatever is left of the body, which is not looked at
+    Rest,
+}
+
+// The frame is complete, so a field which runs off the end of it is never going to be finished
+fn complete<T>(r: nom::IResult<T, (), parser::ParseFail>) -> Result<T, parser::ParseFail> {
+    match r {
+        Ok((b, ())) => Ok(b),
+        Err(nom::Err::Incomplete(_)) => Err(parser::ParseFail::VarintTruncated),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e),
+    }
+}
+
+fn skip<T: cursor::SliceCursor>(mut b: T, len: usize) -> nom::IResult<T, (), parser::ParseFail> {
+    if !b.has_atleast(len) {
+        return Err(nom::Err::Error(parser::ParseFail::InvalidLength(
+            len as i32,
+        )));
+    }
+    b.advance(len);
+    Ok((b, ()))
+}
+
+impl<T: cursor::DirectBuf> Frame<T> {
+    /// The length of the frame body, not including the length header
+    pub fn payload_len(&self) -> usize {
+        self.data_start.remaining(&self.packet)
+    }
+
+    /// The number of bytes this frame took up on the wire, length header included
+    pub fn wire_len(&self) -> usize {
+        self.packet.cursor().remaining(&self.packet)
+    }
+
+    /// Discards the length header, leaving just the pages holding the frame body
+    pub fn into_body(mut self) -> cursor::Multibytes<T> {
+        self.packet.split_to(&self.data_start);
+        self.packet
+    }
+
+    /// Puts this frame back at the front of the framer it came out of, as though it had never been
+    /// framed. Any partial frame the framer was waiting on will be decoded again from its header.
+    pub fn unframe(self, framer: &mut Framer<T>) {
+        framer.buffered += self.wire_len();
+        framer.ring.prepend(self.packet);
+        framer.state = FramerState::WaitingForHeader;
+        framer.update_pause();
+    }
+
+    // Decodes the packet id at the start of the body, along with a cursor to just past it
+    fn id_cursor(&self) -> Result<(i32, cursor::Cursor), parser::ParseFail> {
+        match parser::varint(self.packet.cursor_view(self.data_start)) {
+            Ok((view, id)) => Ok((id, view.cursor())),
+            // The frame is complete, so there is never any more data coming for the id
+            Err(nom::Err::Incomplete(_)) => Err(parser::ParseFail::VarintTruncated),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e),
+        }
+    }
+
+    /// Decodes the packet id at the start of the body without consuming anything
+    pub fn peek_id(&self) -> Result<i32, parser::ParseFail> {
+        self.id_cursor().map(|(id, _)| id)
+    }
+
+    /// Walks the body without decoding it, checking that the packet id and then each of fields
+    /// holds a well formed varint where it should - one which isn't padded out, and which doesn't
+    /// run off the end of the frame. Anything in the body past the last field is not looked at.
+    pub fn validate_varints(&self, fields: &[Field]) -> Result<(), parser::ParseFail> {
+        let mut b = complete(
+            parser::varint_strict(self.packet.cursor_view(self.data_start)).map(|(b, _)| (b, ())),
+        )?;
+        for field in fields {
+            b = complete(match field {
+                Field::VarInt => parser::varint_strict(b).map(|(b, _)| (b, ())),
+                Field::VarLong => parser::varlong_strict(b).map(|(b, _)| (b, ())),
+                Field::Fixed(len) => skip(b, *len),
+                Field::LengthPrefixed => parser::varint_strict(b).and_then(|(b, len)| {
+                    if len < 0 {
+                        Err(nom::Err::Error(parser::ParseFail::InvalidLength(len)))
+                    } else {
+                        skip(b, len as usize)
+                    }
+                }),
+                Field::Rest => return Ok(()),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Splits off the packet id, leaving just the pages holding the rest of the body
+    pub fn split_id(mut self) -> Result<(i32, cursor::Multibytes<T>), parser::ParseFail> {
+        let (id, c) = self.id_cursor()?;
+        self.packet.split_to(&c);
+        Ok((id, self.packet))
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum FrameError {
     /// We are waiting for a size header. It should be finished in a few bytes, but since we don't
     /// know, we won't hint a size.
     WaitingForHeader,
     /// We are waiting on the rest of packet data. usize is the amount of data we expect to finish
-    /// off this packet.
+    /// off this packet - this is the total still missing from the frame, not just what is missing
+    /// from the last buffer pushed.
     WaitingForData(usize),
-    /// This should be considered fatal - something we didn't expect happened.
-    DecodeError,
+    /// The frame header was bad. The framer can't find the next frame boundary after this, so the
+    /// connection can't carry on, but the reason may still be worth responding to.
+    DecodeError(DecodeError),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The length varint ran on past the five bytes a varint can take
+    OverlongLength,
+    /// The length was more than max_frame_size
+    LengthExceedsMax { len: usize, max: usize },
+    /// The length couldn't be decoded for any other reason, such as being negative
+    MalformedHeader,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::OverlongLength => f.write_str("frame length varint is too long"),
+            DecodeError::LengthExceedsMax { len, max } => write!(
+                f,
+                "frame length {} is more than the maximum of {}",
+                len, max
+            ),
+            DecodeError::MalformedHeader => f.write_str("frame header is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<parser::ParseFail> for DecodeError {
+    fn from(e: parser::ParseFail) -> DecodeError {
+        match e {
+            parser::ParseFail::VarintExceededShift(_) => DecodeError::OverlongLength,
+            _ => DecodeError::MalformedHeader,
+        }
+    }
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::WaitingForHeader => f.write_str("waiting on a frame header"),
+            FrameError::WaitingForData(n) => write!(f, "waiting on {} more bytes of frame", n),
+            FrameError::DecodeError(e) => write!(f, "frame header could not be decoded: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::DecodeError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 struct TailingDataState {
     data_start: cursor::Cursor,
     data_end: cursor::Cursor,
+    len: usize,
 }
 
 enum FramerState {
@@ -49,10 +212,29 @@ enum FramerState {
     WaitingForTailingData(TailingDataState),
 }
 
+/// A snapshot of what a Framer is waiting on, for debugging connections which seem stuck
+#[derive(Debug, PartialEq)]
+pub enum FramerStateInfo {
+    /// No frame header has been decoded yet. buffered is how many bytes are sitting in the framer.
+    WaitingForHeader { buffered: usize },
+    /// The header has been decoded, and the framer is waiting on the rest of the body
+    WaitingForTailingData {
+        /// The body length the header declared
+        frame_len: usize,
+        /// How many bytes of the body have been received so far
+        received: usize,
+    },
+}
+
 pub struct Framer<T: cursor::DirectBuf> {
     pub max_frame_size: usize,
     ring: cursor::Multibytes<T>,
+    // Bytes in ring, kept as pages come and go rather than walking it every time
+    buffered: usize,
     state: FramerState,
+    high_water: usize,
+    low_water: usize,
+    paused: bool,
 }
 
 impl<T: cursor::DirectBuf> Framer<T> {
@@ -60,23 +242,127 @@ impl<T: cursor::DirectBuf> Framer<T> {
         Framer {
             max_frame_size,
             ring: cursor::Multibytes::new(VecDeque::with_capacity(buffer_size)),
+            buffered: 0,
             state: FramerState::WaitingForHeader,
+            high_water: usize::MAX,
+            low_water: usize::MAX,
+            paused: false,
         }
     }
 
     pub fn push_buffer(&mut self, b: T) {
+        self.buffered += b.remaining();
         self.ring.append(b);
+        self.update_pause();
+    }
+
+    /// Once more than high_water bytes are buffered, should_pause will return true until frames
+    /// have been taken out to bring it to low_water or below. By default the framer never pauses.
+    pub fn set_watermarks(&mut self, high_water: usize, low_water: usize) {
+        assert!(low_water <= high_water);
+        self.high_water = high_water;
+        self.low_water = low_water;
+        self.update_pause();
+    }
+
+    /// The number of bytes currently sitting in the framer, including any partial frame
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// True if the reader should stop pushing buffers until more frames have been drained
+    pub fn should_pause(&self) -> bool {
+        self.paused
+    }
+
+    fn update_pause(&mut self) {
+        let buffered = self.buffered();
+        if buffered > self.high_water {
+            self.paused = true;
+        } else if buffered <= self.low_water {
+            self.paused = false;
+        }
+    }
+
+    /// True if the framer is sitting on a frame boundary - there is no partial frame buffered.
+    pub fn is_idle(&self) -> bool {
+        match self.state {
+            FramerState::WaitingForHeader => self.ring.is_empty(),
+            FramerState::WaitingForTailingData(_) => false,
+        }
+    }
+
+    /// The total number of bytes still needed to finish the frame currently being waited on, or
+    /// None if the framer doesn't know yet (it is still waiting on a frame header).
+    pub fn bytes_needed(&self) -> Option<usize> {
+        match &self.state {
+            FramerState::WaitingForHeader => None,
+            FramerState::WaitingForTailingData(state) => {
+                let mut data_end = state.data_end;
+                data_end.true_up(&self.ring);
+                Some(data_end.run_off_end(&self.ring))
+            }
+        }
+    }
+
+    /// What the framer is waiting on right now, and how far along it is
+    pub fn state_debug(&self) -> FramerStateInfo {
+        match &self.state {
+            FramerState::WaitingForHeader => FramerStateInfo::WaitingForHeader {
+                buffered: self.buffered(),
+            },
+            FramerState::WaitingForTailingData(state) => FramerStateInfo::WaitingForTailingData {
+                frame_len: state.len,
+                received: std::cmp::min(state.data_start.remaining(&self.ring), state.len),
+            },
+        }
+    }
+
+    /// Consumes the framer, handing back whatever is still buffered in it - the header and as
+    /// much of the body of a frame as arrived, if one was partway in.
+    pub fn into_remaining(mut self) -> cursor::Multibytes<T> {
+        self.ring.trim_empty();
+        self.ring
     }
 
     pub fn frame(&mut self) -> Result<Frame<T>, FrameError> {
+        let f = self.next_frame();
+        if let Ok(frame) = &f {
+            self.buffered -= frame.wire_len();
+            self.update_pause();
+        }
+        f
+    }
+
+    /// Frames at most max frames, so that a peer sending a flood of tiny frames can't keep a task
+    /// busy forever. The error which stopped framing is returned alongside the frames, or None if
+    /// the cap was hit first, in which case there may be more frames ready.
+    pub fn frame_batch(&mut self, max: usize) -> (Vec<Frame<T>>, Option<FrameError>) {
+        let mut frames = Vec::new();
+        while frames.len() < max {
+            match self.frame() {
+                Ok(f) => frames.push(f),
+                Err(e) => return (frames, Some(e)),
+            }
+        }
+        (frames, None)
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<T>, FrameError> {
         match &mut self.state {
             FramerState::WaitingForHeader => {
                 // Attempt to decode a header
                 let header_view = self.ring.view();
                 match parser::varint(header_view) {
                     Ok((view, len)) => {
-                        if len < 0 || len as usize > self.max_frame_size {
-                            return Err(FrameError::DecodeError);
+                        if len < 0 {
+                            return Err(FrameError::DecodeError(DecodeError::MalformedHeader));
+                        }
+                        if len as usize > self.max_frame_size {
+                            return Err(FrameError::DecodeError(DecodeError::LengthExceedsMax {
+                                len: len as usize,
+                                max: self.max_frame_size,
+                            }));
                         }
 
                         let data_start = view.cursor();
@@ -100,6 +386,7 @@ impl<T: cursor::DirectBuf> Framer<T> {
                             self.state = FramerState::WaitingForTailingData(TailingDataState {
                                 data_start,
                                 data_end,
+                                len: len as usize,
                             });
 
                             return Err(FrameError::WaitingForData(
@@ -111,10 +398,10 @@ impl<T: cursor::DirectBuf> Framer<T> {
                         // We don't have enough, no progression.
                         return Err(FrameError::WaitingForHeader);
                     }
-                    Err(nom::Err::Error(_)) | Err(nom::Err::Failure(_)) => {
+                    Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                         // The parser probably overran - whatever is on the other end of this
                         // sent us bad data. Fatal the framer
-                        return Err(FrameError::DecodeError);
+                        return Err(FrameError::DecodeError(e.into()));
                     }
                 }
             }
@@ -142,6 +429,25 @@ impl<T: cursor::DirectBuf> Framer<T> {
     }
 }
 
+impl<T: cursor::DirectBufMut> Framer<T> {
+    /// Hands f every byte buffered past the frame currently being waited on to change in place, a
+    /// page at a time. This is for data which was pushed in before it could be made sense of, such
+    /// as bytes which turned out to be encrypted.
+    pub fn for_each_unframed_mut<F: FnMut(&mut [u8])>(&mut self, f: F) {
+        let start = match &mut self.state {
+            FramerState::WaitingForHeader => self.ring.cursor(),
+            FramerState::WaitingForTailingData(state) => {
+                if !state.data_end.true_up(&self.ring) {
+                    // The frame hasn't all arrived yet, so there is nothing past it
+                    return;
+                }
+                state.data_end
+            }
+        };
+        self.ring.for_each_mut_from(&start, f);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -173,13 +479,40 @@ mod tests {
         };
     }
 
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            FrameError::WaitingForHeader.to_string(),
+            "waiting on a frame header"
+        );
+        assert_eq!(
+            FrameError::WaitingForData(12).to_string(),
+            "waiting on 12 more bytes of frame"
+        );
+        assert_eq!(
+            FrameError::DecodeError(DecodeError::OverlongLength).to_string(),
+            "frame header could not be decoded: frame length varint is too long"
+        );
+        assert_eq!(
+            DecodeError::LengthExceedsMax { len: 129, max: 128 }.to_string(),
+            "frame length 129 is more than the maximum of 128"
+        );
+        assert_eq!(
+            DecodeError::MalformedHeader.to_string(),
+            "frame header is malformed"
+        );
+    }
+
     #[test]
     fn max_frame_size() {
         let mut f = Framer::new(128, 1);
         // Prefix length of 129
-        let b = to_buf!([0x80, 0x02]);
+        let b = to_buf!([0x81, 0x01]);
         f.push_buffer(b);
-        assert_eq!(f.frame().unwrap_err(), FrameError::DecodeError);
+        assert_eq!(
+            f.frame().unwrap_err(),
+            FrameError::DecodeError(DecodeError::LengthExceedsMax { len: 129, max: 128 })
+        );
     }
 
     #[test]
@@ -188,7 +521,22 @@ mod tests {
         // Invalid varint should result in an error
         let b = to_buf!([0x80, 0x80, 0x80, 0x80, 0x80, 0x02]);
         f.push_buffer(b);
-        assert_eq!(f.frame().unwrap_err(), FrameError::DecodeError);
+        assert_eq!(
+            f.frame().unwrap_err(),
+            FrameError::DecodeError(DecodeError::OverlongLength)
+        );
+    }
+
+    #[test]
+    fn negative_length() {
+        let mut f = Framer::new(128, 1);
+        // -1
+        let b = to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f]);
+        f.push_buffer(b);
+        assert_eq!(
+            f.frame().unwrap_err(),
+            FrameError::DecodeError(DecodeError::MalformedHeader)
+        );
     }
 
     #[test]
@@ -203,6 +551,19 @@ mod tests {
         assert_eq!(eof.unwrap_err(), FrameError::WaitingForHeader);
     }
 
+    #[test]
+    fn idle() {
+        let mut f = Framer::new(128, 1);
+        assert!(f.is_idle());
+        f.push_buffer(to_buf!([0x3, 0x0]));
+        assert!(!f.is_idle());
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(2));
+        assert!(!f.is_idle());
+        f.push_buffer(to_buf!([0x1, 0x2]));
+        validate_frame!(f.frame().unwrap(), 3);
+        assert!(f.is_idle());
+    }
+
     #[test]
     fn single_frame_multi_invoke() {
         let mut f = Framer::new(128, 1);
@@ -241,4 +602,283 @@ mod tests {
         validate_frame!(f.frame().unwrap(), 2);
         assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(3));
     }
+
+    #[test]
+    fn frame_batch() {
+        let mut f = Framer::new(128, 1);
+        f.push_buffer(to_buf!([
+            0x1, 0xa, 0x1, 0xb, 0x1, 0xc, 0x1, 0xd, 0x1, 0xe, 0x2
+        ]));
+
+        let (frames, e) = f.frame_batch(3);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(e, None);
+        assert_eq!(f.buffered(), 5);
+
+        let (frames, e) = f.frame_batch(3);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(e, Some(FrameError::WaitingForData(2)));
+        for frame in frames {
+            validate_frame!(frame, 1);
+        }
+
+        let (frames, e) = f.frame_batch(0);
+        assert!(frames.is_empty());
+        assert_eq!(e, None);
+    }
+
+    #[test]
+    fn lengths() {
+        let mut f = Framer::new(512, 1);
+        f.push_buffer(to_buf!([0x3, 0x0, 0x1]));
+        f.push_buffer(to_buf!([0x2, 0xac]));
+        f.push_buffer(to_buf!([0x02]));
+        f.push_buffer(to_buf!([0x5; 300]));
+
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.payload_len(), 3);
+        assert_eq!(frame.wire_len(), 1 + 3);
+
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.payload_len(), 300);
+        assert_eq!(frame.wire_len(), 2 + 300);
+    }
+
+    #[test]
+    fn zero_length() {
+        use bytes::Buf;
+
+        let mut f = Framer::new(128, 1);
+        f.push_buffer(to_buf!([0x0, 0x0, 0x2, 0x7]));
+        for _ in 0..2 {
+            let frame = f.frame().unwrap();
+            validate_frame!(&frame, 0);
+            assert_eq!(frame.payload_len(), 0);
+            assert_eq!(frame.into_body().remaining(), 0);
+        }
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(1));
+        f.push_buffer(to_buf!([0x8]));
+        let frame = f.frame().unwrap();
+        assert_eq!(&frame.into_body().to_bytes()[..], &[0x7, 0x8]);
+
+        // A header which ends its page, with nothing after it yet
+        f.push_buffer(to_buf!([0x0]));
+        let frame = f.frame().unwrap();
+        validate_frame!(&frame, 0);
+        assert_eq!(frame.payload_len(), 0);
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForHeader);
+        assert!(f.is_idle());
+
+        f.push_buffer(to_buf!([0x1, 0x9]));
+        let frame = f.frame().unwrap();
+        assert_eq!(&frame.into_body().to_bytes()[..], &[0x9]);
+    }
+
+    #[test]
+    fn into_body() {
+        use bytes::Buf;
+
+        let mut f = Framer::new(256, 1);
+        // Header for a frame of 130, split across pages along with the body
+        f.push_buffer(to_buf!([0x82]));
+        f.push_buffer(to_buf!([0x01, 0, 1, 2]));
+        f.push_buffer(to_buf!([3; 127]));
+        f.push_buffer(to_buf!([0x2, 0x4, 0x5]));
+
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.payload_len(), 130);
+        let mut body = frame.into_body();
+        assert_eq!(body.remaining(), 130);
+        assert_eq!(&body.to_bytes()[..5], &[0, 1, 2, 3, 3]);
+
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.payload_len(), 2);
+        assert_eq!(&frame.into_body().to_bytes()[..], &[0x4, 0x5]);
+    }
+
+    #[test]
+    fn packet_id() {
+        use bytes::Buf;
+
+        let mut f = Framer::new(128, 1);
+        // A two byte packet id of 128, split across pages
+        f.push_buffer(to_buf!([0x3, 0x80]));
+        f.push_buffer(to_buf!([0x01, 0xaa, 0x1, 0x80]));
+
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.peek_id(), Ok(128));
+        let (id, mut body) = frame.split_id().unwrap();
+        assert_eq!(id, 128);
+        assert_eq!(&body.to_bytes()[..], &[0xaa]);
+
+        // The id can't continue on past the end of the frame
+        let frame = f.frame().unwrap();
+        assert_eq!(frame.peek_id(), Err(parser::ParseFail::VarintTruncated));
+        assert!(frame.split_id().is_err());
+    }
+
+    #[test]
+    fn validate_varints() {
+        let mut f = Framer::new(128, 1);
+        // An id of 1, a string of "hi", a varlong of 300 and 2 more bytes
+        f.push_buffer(to_buf!([0x8, 0x1, 0x2, 0x68, 0x69]));
+        f.push_buffer(to_buf!([0xac, 0x02, 0x7, 0x7]));
+        // An id of 1, padded out to 3 bytes
+        f.push_buffer(to_buf!([0x4, 0x81, 0x80, 0x00, 0x0]));
+        // A string claiming to be longer than the frame
+        f.push_buffer(to_buf!([0x3, 0x1, 0x5, 0x0]));
+
+        let frame = f.frame().unwrap();
+        let fields = [Field::LengthPrefixed, Field::VarLong, Field::Fixed(2)];
+        assert_eq!(frame.validate_varints(&fields), Ok(()));
+        assert_eq!(
+            frame.validate_varints(&[Field::LengthPrefixed, Field::Rest, Field::VarInt]),
+            Ok(())
+        );
+        // There is nothing left for another field
+        assert_eq!(
+            frame.validate_varints(&[
+                Field::LengthPrefixed,
+                Field::VarLong,
+                Field::Fixed(2),
+                Field::VarInt
+            ]),
+            Err(parser::ParseFail::VarintTruncated)
+        );
+
+        let frame = f.frame().unwrap();
+        assert_eq!(
+            frame.validate_varints(&[]),
+            Err(parser::ParseFail::VarintOverlong(3))
+        );
+
+        let frame = f.frame().unwrap();
+        assert_eq!(
+            frame.validate_varints(&[Field::LengthPrefixed]),
+            Err(parser::ParseFail::InvalidLength(5))
+        );
+    }
+
+    #[test]
+    fn into_remaining() {
+        use bytes::Buf;
+
+        let mut f = Framer::new(256, 1);
+        f.push_buffer(to_buf!([0x2, 0x5, 0x6, 0x4, 0x1]));
+        f.push_buffer(to_buf!([0x2]));
+        assert!(f.frame().is_ok());
+        assert_eq!(f.frame().err(), Some(FrameError::WaitingForData(2)));
+
+        let mut rest = f.into_remaining();
+        assert_eq!(rest.to_bytes(), &[0x4, 0x1, 0x2][..]);
+    }
+
+    #[test]
+    fn state_debug() {
+        let mut f = Framer::new(256, 1);
+        assert_eq!(
+            f.state_debug(),
+            FramerStateInfo::WaitingForHeader { buffered: 0 }
+        );
+
+        // Half of a two byte header
+        f.push_buffer(to_buf!([0x8a]));
+        assert_eq!(f.frame().err(), Some(FrameError::WaitingForHeader));
+        assert_eq!(
+            f.state_debug(),
+            FramerStateInfo::WaitingForHeader { buffered: 1 }
+        );
+
+        f.push_buffer(to_buf!([0x01, 0, 1, 2]));
+        assert!(f.frame().is_err());
+        assert_eq!(
+            f.state_debug(),
+            FramerStateInfo::WaitingForTailingData {
+                frame_len: 138,
+                received: 3
+            }
+        );
+
+        // Received counts only this frame's body, even once the next frame starts arriving
+        f.push_buffer(to_buf!([3; 140]));
+        assert_eq!(
+            f.state_debug(),
+            FramerStateInfo::WaitingForTailingData {
+                frame_len: 138,
+                received: 138
+            }
+        );
+        assert!(f.frame().is_ok());
+        assert_eq!(
+            f.state_debug(),
+            FramerStateInfo::WaitingForHeader { buffered: 5 }
+        );
+    }
+
+    #[test]
+    fn watermarks() {
+        let mut f = Framer::new(128, 1);
+        f.set_watermarks(8, 3);
+
+        f.push_buffer(to_buf!([0x3, 0x0, 0x1, 0x2, 0x3, 0x0, 0x1, 0x2]));
+        assert_eq!(f.buffered(), 8);
+        assert!(!f.should_pause());
+        f.push_buffer(to_buf!([0x1, 0x0, 0x1]));
+        assert!(f.should_pause());
+
+        // Between the marks, so still paused
+        validate_frame!(f.frame().unwrap(), 3);
+        assert_eq!(f.buffered(), 7);
+        assert!(f.should_pause());
+
+        validate_frame!(f.frame().unwrap(), 3);
+        assert_eq!(f.buffered(), 3);
+        assert!(!f.should_pause());
+
+        // Coming back up between the marks doesn't pause again
+        f.push_buffer(to_buf!([0x0, 0x1, 0x2]));
+        assert!(!f.should_pause());
+    }
+
+    #[test]
+    fn unframe() {
+        use bytes::Buf;
+
+        let mut f = Framer::new(128, 1);
+        f.push_buffer(to_buf!([0x3, 0x0]));
+        f.push_buffer(to_buf!([0x1, 0x2, 0x2, 0x0]));
+
+        let frame = f.frame().unwrap();
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(1));
+        frame.unframe(&mut f);
+        assert_eq!(f.buffered(), 6);
+
+        let mut again = f.frame().unwrap();
+        assert_eq!(&again.packet.to_bytes()[..], &[0x3, 0x0, 0x1, 0x2]);
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(1));
+
+        f.push_buffer(to_buf!([0x1]));
+        validate_frame!(f.frame().unwrap(), 2);
+        assert!(f.is_idle());
+    }
+
+    #[test]
+    fn bytes_needed() {
+        let mut f = Framer::new(128, 1);
+        assert_eq!(f.bytes_needed(), None);
+
+        // Header for a frame of 100, with only 10 bytes of it present
+        f.push_buffer(to_buf!([100, 0, 1, 2, 3, 4]));
+        f.push_buffer(to_buf!([5, 6, 7, 8, 9]));
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(90));
+        assert_eq!(f.bytes_needed(), Some(90));
+
+        f.push_buffer(to_buf!([0; 40]));
+        assert_eq!(f.bytes_needed(), Some(50));
+        assert_eq!(f.frame().unwrap_err(), FrameError::WaitingForData(50));
+
+        f.push_buffer(to_buf!([0; 50]));
+        validate_frame!(f.frame().unwrap(), 100);
+        assert_eq!(f.bytes_needed(), None);
+    }
 }
@@ -15,20 +15,71 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use super::compress::Inflater;
+use super::compress::{Inflater, SizedProcessError};
 use super::cursor;
 use super::framer;
 use super::mempool;
 use super::parser;
+use super::protocol;
 use crate::zlib;
 
 #[derive(Debug, PartialEq)]
 pub enum InflaterError {
     CompressionSizeDecodeFail,
     SmallCompression,
+    /// The packet didn't inflate to the size it said it would, which the protocol forbids. actual
+    /// is None if it would have inflated to more - inflating is cut off once it gets that far.
+    SizeMismatch {
+        declared: usize,
+        actual: Option<usize>,
+    },
+    /// Inflating the packet would take the connection past its max_total_inflated
+    InflateBudgetExceeded,
     ZlibError(zlib::ZLibError),
 }
 
+impl std::fmt::Display for InflaterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InflaterError::CompressionSizeDecodeFail => {
+                f.write_str("packet data length could not be decoded")
+            }
+            InflaterError::SmallCompression => {
+                f.write_str("packet was compressed despite being under the threshold")
+            }
+            InflaterError::SizeMismatch {
+                declared,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "packet inflated to {} bytes, but declared {}",
+                actual, declared
+            ),
+            InflaterError::SizeMismatch {
+                declared,
+                actual: None,
+            } => write!(
+                f,
+                "packet inflated to more than the {} bytes it declared",
+                declared
+            ),
+            InflaterError::InflateBudgetExceeded => {
+                f.write_str("connection has inflated more data than it is allowed")
+            }
+            InflaterError::ZlibError(e) => write!(f, "packet could not be inflated: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InflaterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InflaterError::ZlibError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<zlib::ZLibError> for InflaterError {
     fn from(z: zlib::ZLibError) -> InflaterError {
         InflaterError::ZlibError(z)
@@ -41,8 +92,10 @@ pub enum DataBacking<T: cursor::DirectBuf> {
 }
 
 pub struct Packet<T: cursor::DirectBuf> {
-    h: cursor::Multibytes<T>,
-    d: DataBacking<T>,
+    pub h: cursor::Multibytes<T>,
+    pub d: DataBacking<T>,
+    /// The state the connection was in when this packet arrived
+    pub state: protocol::ProtocolState,
 }
 
 struct InflateState {
@@ -52,11 +105,41 @@ struct InflateState {
 
 pub struct PacketInflater {
     inflate: Option<InflateState>,
+    state: protocol::ProtocolState,
+    total_inflated: usize,
+    max_total_inflated: Option<usize>,
 }
 
 impl PacketInflater {
     pub fn new() -> PacketInflater {
-        PacketInflater { inflate: None }
+        PacketInflater {
+            inflate: None,
+            state: protocol::ProtocolState::default(),
+            total_inflated: 0,
+            max_total_inflated: None,
+        }
+    }
+
+    /// Caps how many bytes may be inflated over the life of this inflater, across every packet.
+    /// Each packet is checked against this by its declared size before it is inflated, and is
+    /// never inflated past that size, so the cap holds however the packets' sizes are lied about.
+    /// Once a packet would take the total past this, inflate fails with InflateBudgetExceeded.
+    pub fn set_max_total_inflated(&mut self, max: Option<usize>) {
+        self.max_total_inflated = max;
+    }
+
+    /// The number of bytes inflated so far, counting only packets which inflated successfully
+    pub fn total_inflated(&self) -> usize {
+        self.total_inflated
+    }
+
+    /// Packets inflated after this are marked as arriving in the given state
+    pub fn set_state(&mut self, state: protocol::ProtocolState) {
+        self.state = state;
+    }
+
+    pub fn state(&self) -> protocol::ProtocolState {
+        self.state
     }
 
     pub fn inflate<'a, T: cursor::DirectBufMut, Alloc: mempool::BlockAllocator<'a, T>>(
@@ -75,24 +158,55 @@ impl PacketInflater {
                         Ok(Packet {
                             h: data,
                             d: DataBacking::Cursor(cursor),
+                            state: self.state,
                         })
                     } else if decompressed_size < compress.threshold {
                         // This is an error, protocol dictates we should yeet the client at the
                         // other end for daring to send us such misformatted data
                         Err(InflaterError::SmallCompression)
                     } else {
+                        // Check the budget against the declared size so we don't bother inflating
+                        // a packet that would blow it anyway. Inflating is held to this size below,
+                        // so the budget can't be overrun by a packet that declares less than it has
+                        let total = self.total_inflated + decompressed_size as usize;
+                        if let Some(max) = self.max_total_inflated {
+                            if total > max {
+                                return Err(InflaterError::InflateBudgetExceeded);
+                            }
+                        }
+
                         // Segment the header from the data so that we can decompress the data
                         let (mut data, cursor) = compressed_data.dissolve();
                         let header = data.split_to(&cursor);
 
-                        // frame.packet now contains the compressed data
-                        // TODO: Constrain inflation to the size that was given us - this trusts
-                        // user input :(
-                        let inflated = compress.inflater.process(data, alloc)?;
+                        // frame.packet now contains the compressed data. Every packet is its own
+                        // zlib stream, so nothing from the last packet can carry over. Inflating
+                        // stops as soon as it goes past the declared size.
+                        compress.inflater.reset();
+                        let declared = decompressed_size as usize;
+                        let inflated = match compress.inflater.process_sized(data, declared, alloc)
+                        {
+                            Ok(inflated) => inflated,
+                            Err(SizedProcessError::ZLibError(e)) => return Err(e.into()),
+                            Err(SizedProcessError::OutputOverrun) => {
+                                return Err(InflaterError::SizeMismatch {
+                                    declared,
+                                    actual: None,
+                                })
+                            }
+                            Err(SizedProcessError::OutputUnderrun(n)) => {
+                                return Err(InflaterError::SizeMismatch {
+                                    declared,
+                                    actual: Some(n),
+                                })
+                            }
+                        };
+                        self.total_inflated = total;
 
                         Ok(Packet {
                             h: header,
                             d: DataBacking::Multibytes(inflated),
+                            state: self.state,
                         })
                     }
                 }
@@ -103,6 +217,7 @@ impl PacketInflater {
             Ok(Packet {
                 h: frame.packet,
                 d: DataBacking::Cursor(frame.data_start),
+                state: self.state,
             })
         }
     }
@@ -115,6 +230,15 @@ impl PacketInflater {
 
         Ok(())
     }
+
+    /// Packets inflated after this are taken to be uncompressed again
+    pub fn stop_compression(&mut self) {
+        self.inflate = None;
+    }
+
+    pub fn compression_threshold(&self) -> Option<i32> {
+        self.inflate.as_ref().map(|i| i.threshold)
+    }
 }
 
 #[cfg(test)]
@@ -124,9 +248,7 @@ mod tests {
 
     fn frame_of(s: Vec<u8>) -> framer::Frame<bytes::BytesMut> {
         let b = bytes::BytesMut::from_iter(s.iter());
-        let mut vd = std::collections::VecDeque::new();
-        vd.push_back(b);
-        let mb = cursor::Multibytes::new(vd);
+        let mb = cursor::Multibytes::from_buf(b);
         let c = mb.cursor();
         framer::Frame {
             packet: mb,
@@ -134,6 +256,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn error_display() {
+        use std::error::Error;
+
+        assert_eq!(
+            InflaterError::CompressionSizeDecodeFail.to_string(),
+            "packet data length could not be decoded"
+        );
+        assert_eq!(
+            InflaterError::SmallCompression.to_string(),
+            "packet was compressed despite being under the threshold"
+        );
+        assert_eq!(
+            InflaterError::SizeMismatch {
+                declared: 5,
+                actual: Some(4)
+            }
+            .to_string(),
+            "packet inflated to 4 bytes, but declared 5"
+        );
+        assert_eq!(
+            InflaterError::SizeMismatch {
+                declared: 5,
+                actual: None
+            }
+            .to_string(),
+            "packet inflated to more than the 5 bytes it declared"
+        );
+
+        assert_eq!(
+            InflaterError::InflateBudgetExceeded.to_string(),
+            "connection has inflated more data than it is allowed"
+        );
+
+        let e = InflaterError::ZlibError(zlib::ZLibError::DataError);
+        assert_eq!(
+            e.to_string(),
+            "packet could not be inflated: zlib input data is corrupt"
+        );
+        assert!(e.source().is_some());
+    }
+
     #[test]
     fn packetinflater_no_inflater() {
         let alloc = mempool::SystemMemPool { buf_size: 12 };
@@ -215,6 +379,161 @@ mod tests {
         }
     }
 
+    #[test]
+    fn packetinflater_consecutive_compression() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut inflater = PacketInflater::new();
+        inflater.start_compression(3).unwrap();
+
+        // Each packet is a complete zlib stream of its own
+        for _ in 0..2 {
+            let frame = frame_of(vec![0x4, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11]);
+            let result = inflater.inflate(frame, &alloc).unwrap();
+            if let DataBacking::Multibytes(mb) = result.d {
+                let mut view = mb.view();
+                assert_eq!(view.get_u32(), 0x01020304);
+                assert_eq!(view.remaining(), 0);
+            } else {
+                panic!("non-mb");
+            }
+        }
+    }
+
+    #[test]
+    fn packetinflater_round_trip() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut deflater = crate::compress::Deflater::deflate(5).unwrap();
+        let mut inflater = PacketInflater::new();
+        inflater.start_compression(4).unwrap();
+
+        let body = |b: &[u8]| cursor::Multibytes::from_buf(bytes::BytesMut::from(b));
+        let frame = |packet: cursor::Multibytes<bytes::BytesMut>| {
+            let data_start = packet.cursor();
+            framer::Frame { packet, data_start }
+        };
+
+        // Under the threshold, so it is passed along uncompressed
+        let packet = deflater
+            .frame_compressed(body(&[1, 2, 3]), 4, &alloc)
+            .unwrap();
+        let result = inflater.inflate(frame(packet), &alloc).unwrap();
+        if let DataBacking::Cursor(c) = result.d {
+            let mut view = result.h.cursor_view(c);
+            assert_eq!(view.to_bytes(), &[1, 2, 3][..]);
+        } else {
+            panic!("non-cursor");
+        }
+
+        for _ in 0..2 {
+            let packet = deflater
+                .frame_compressed(body(&[1, 2, 3, 4, 5]), 4, &alloc)
+                .unwrap();
+            let result = inflater.inflate(frame(packet), &alloc).unwrap();
+            if let DataBacking::Multibytes(mut mb) = result.d {
+                assert_eq!(mb.to_bytes(), &[1, 2, 3, 4, 5][..]);
+            } else {
+                panic!("non-mb");
+            }
+        }
+    }
+
+    #[test]
+    fn packetinflater_size_mismatch() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut inflater = PacketInflater::new();
+        inflater.start_compression(3).unwrap();
+
+        // Same data as above, which inflates to 4 bytes, but claims to be 5
+        let frame = frame_of(vec![0x5, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11]);
+        let result = inflater.inflate(frame, &alloc);
+        if let Err(e) = result {
+            assert_eq!(
+                e,
+                InflaterError::SizeMismatch {
+                    declared: 5,
+                    actual: Some(4)
+                }
+            );
+        } else {
+            panic!("valid response");
+        }
+    }
+
+    #[test]
+    fn packetinflater_oversized() {
+        use mempool::BlockAllocator;
+
+        let alloc = mempool::CountingMemPool::new(12);
+        let mut inflater = PacketInflater::new();
+        inflater.start_compression(3).unwrap();
+
+        // Declares 4 bytes, but carries a megabyte of zeroes
+        let compressed = crate::compress::deflate_bytes(&vec![0; 1 << 20], 9).unwrap();
+        let mut page = alloc.allocate();
+        cursor::DirectBuf::truncate(&mut page, compressed.len() + 1);
+        page[0] = 0x4;
+        page[1..].copy_from_slice(&compressed);
+        let packet = cursor::Multibytes::from_buf(page);
+        let data_start = packet.cursor();
+
+        let before = alloc.allocations();
+        match inflater.inflate(framer::Frame { packet, data_start }, &alloc) {
+            Err(e) => assert_eq!(
+                e,
+                InflaterError::SizeMismatch {
+                    declared: 4,
+                    actual: None
+                }
+            ),
+            Ok(_) => panic!("valid response"),
+        }
+        // Splitting off the header counts as one allocation, and the 4 declared bytes only ever
+        // needed the one output page on top of that
+        assert_eq!(alloc.allocations() - before, 2);
+    }
+
+    #[test]
+    fn packetinflater_budget() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut inflater = PacketInflater::new();
+        inflater.start_compression(3).unwrap();
+        inflater.set_max_total_inflated(Some(10));
+
+        let packet = || frame_of(vec![0x4, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11]);
+        inflater.inflate(packet(), &alloc).unwrap();
+
+        // A packet holding more than it declares is cut off at its declared size, and doesn't
+        // count against the budget either
+        let liar = frame_of(vec![0x3, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11]);
+        match inflater.inflate(liar, &alloc) {
+            Err(e) => assert_eq!(
+                e,
+                InflaterError::SizeMismatch {
+                    declared: 3,
+                    actual: None
+                }
+            ),
+            Ok(_) => panic!("valid response"),
+        }
+        assert_eq!(inflater.total_inflated(), 4);
+
+        inflater.inflate(packet(), &alloc).unwrap();
+        assert_eq!(inflater.total_inflated(), 8);
+
+        // Uncompressed packets don't count against the budget
+        inflater
+            .inflate(frame_of(vec![0x0, 0x3, 0x3]), &alloc)
+            .unwrap();
+        assert_eq!(inflater.total_inflated(), 8);
+
+        // A third packet would take us to 12
+        match inflater.inflate(packet(), &alloc) {
+            Err(e) => assert_eq!(e, InflaterError::InflateBudgetExceeded),
+            Ok(_) => panic!("valid response"),
+        }
+        assert_eq!(inflater.total_inflated(), 8);
+    }
+
     /*
     #[test]
     fn packetizer_normal() {
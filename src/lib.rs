@@ -39,8 +39,10 @@ pub mod inflater;
 pub mod mbedtls;
 pub mod packet;
 pub mod parser;
+pub mod protocol;
 pub mod ring;
 pub mod socket;
+pub mod stream;
 pub mod zlib;
 
 #[cfg(test)]
@@ -42,7 +42,7 @@ impl MbedAesContext {
 }
 
 #[repr(i32)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CryptMode {
     Encrypt = 1,
     Decrypt = 0,
@@ -50,7 +50,7 @@ pub enum CryptMode {
 
 #[link(name = "mbedcrypto", kind = "static")]
 extern "C" {
-    // fn mbedtls_aes_init(ctx: *const MbedAesContext);
+    fn mbedtls_aes_init(ctx: *const MbedAesContext);
     fn mbedtls_aes_free(ctx: *const MbedAesContext);
     fn mbedtls_aes_setkey_enc(
         ctx: *const MbedAesContext,
@@ -78,14 +78,16 @@ impl AesCryptCfb8 {
     pub fn new(key: [c_uchar; 16]) -> AesCryptCfb8 {
         // SAFETY: idk looks safe to me
         unsafe {
+            // Zeroed first so that off, which mbedtls doesn't know about, starts out defined too
             let mut b = AesCryptCfb8 {
                 ctx: MaybeUninit::zeroed().assume_init(),
                 iv: key,
                 _pin: std::marker::PhantomPinned {},
             };
 
-            // Current implementation simply zeros this pointer - its already zeroed, so we don't
-            // have to worry about it
+            // Zeroing happens to be all that init does today, but the API wants it called before
+            // anything else regardless
+            mbedtls_aes_init(&b.ctx);
             assert!(mbedtls_aes_setkey_enc(&b.ctx, b.iv.as_ptr(), 16 * 8) == 0);
 
             b.ctx.solidify_off();
@@ -127,6 +129,22 @@ impl Drop for AesCryptCfb8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn cfb8_known_answer() {
+        let key: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let mut msg: [u8; 7] = [0, 1, 2, 3, 4, 5, 6];
+
+        let mut enc = AesCryptCfb8::new(key);
+        enc.process(&mut msg, CryptMode::Encrypt);
+        assert_eq!(msg, [0x0a, 0x22, 0xf7, 0x96, 0xe1, 0xb9, 0x3e]);
+
+        let mut dec = AesCryptCfb8::new(key);
+        dec.process(&mut msg[..3], CryptMode::Decrypt);
+        dec.process(&mut msg[3..], CryptMode::Decrypt);
+        assert_eq!(msg, [0, 1, 2, 3, 4, 5, 6]);
+    }
+
     #[test]
     fn bindgen_test_layout_MbedAesContext() {
         assert!(
@@ -21,17 +21,157 @@ extern crate memmap;
 use core::mem::MaybeUninit;
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::Backoff;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::ops::Deref;
 use std::ops::DerefMut;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 use super::cursor::{DirectBuf, DirectBufMut};
 
 pub struct GlobalMemPoolSettings {
     pub buf_size: usize,
+    /// How many buffers are in the first page mapped
     pub page_entries: usize,
+    /// Each page after the first has twice the entries of the last, until this many. None, the
+    /// default, keeps every page the same size as the first.
+    pub max_page_entries: Option<usize>,
     pub concurrent_allocation_limit: u64,
+    /// How many times to back off waiting for other threads to finish mapping pages before giving
+    /// up. None, the default, waits for as long as it takes. Pages allocate maps regardless after
+    /// giving up are counted by GlobalMemPool::overflow_mappings.
+    pub max_backoff_iterations: Option<u32>,
+    /// Bytes left free at the front of every Part handed out, which Part::reclaim_headroom can
+    /// give back, such as for prepending a length without copying the body behind it. 0 (no
+    /// headroom) by default.
+    pub headroom: usize,
+}
+
+impl Default for GlobalMemPoolSettings {
+    /// The settings the builder starts out with
+    fn default() -> Self {
+        GlobalMemPoolSettings::builder()
+            .build()
+            .expect("the builder's defaults are valid")
+    }
+}
+
+impl GlobalMemPoolSettings {
+    pub fn builder() -> GlobalMemPoolSettingsBuilder {
+        GlobalMemPoolSettingsBuilder {
+            buffer_bytes: 4096,
+            buffers_per_page: 64,
+            max_buffers_per_page: None,
+            concurrent_allocation_limit: 1,
+            max_backoff_iterations: None,
+            headroom: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SettingsError {
+    /// Buffer sizes have to be a power of two
+    BufferNotPowerOfTwo(usize),
+    /// The buffer can't fit the refcount sentinel with room left over for data
+    BufferTooSmall(usize),
+    NoBuffersPerPage,
+    /// Pages can only grow, so the most buffers per page can't be below the starting amount
+    MaxBuffersPerPageTooSmall,
+    NoConcurrentAllocations,
+    /// A page of buffers would be larger than the address space
+    PageTooLarge,
+    /// The headroom would leave no room in a buffer for data
+    HeadroomTooLarge(usize),
+}
+
+/// Builds GlobalMemPoolSettings in terms of bytes and counts, checking that they make sense. By
+/// default, buffers are 4KB with 64 to a page and one page allocated at a time.
+pub struct GlobalMemPoolSettingsBuilder {
+    buffer_bytes: usize,
+    buffers_per_page: usize,
+    max_buffers_per_page: Option<usize>,
+    concurrent_allocation_limit: u64,
+    max_backoff_iterations: Option<u32>,
+    headroom: usize,
+}
+
+impl GlobalMemPoolSettingsBuilder {
+    /// The size of each buffer in bytes, which has to be a power of two. A few bytes of each buffer
+    /// are taken up by the refcount sentinel.
+    pub fn buffer_bytes(mut self, buffer_bytes: usize) -> Self {
+        self.buffer_bytes = buffer_bytes;
+        self
+    }
+
+    /// How many buffers are carved out of each mapping
+    pub fn buffers_per_page(mut self, buffers_per_page: usize) -> Self {
+        self.buffers_per_page = buffers_per_page;
+        self
+    }
+
+    /// Lets each mapping after the first hold twice as many buffers as the last, up to this many,
+    /// so that a burst of allocations needs fewer mappings. By default every page is the same size.
+    pub fn max_buffers_per_page(mut self, max_buffers_per_page: usize) -> Self {
+        self.max_buffers_per_page = Some(max_buffers_per_page);
+        self
+    }
+
+    /// How many threads may be mapping new pages at once
+    pub fn concurrent_allocation_limit(mut self, limit: u64) -> Self {
+        self.concurrent_allocation_limit = limit;
+        self
+    }
+
+    /// Gives up on waiting for other threads to map pages after backing off this many times. Past
+    /// that, try_allocate fails with TimedOut and allocate maps a page regardless of the
+    /// concurrent_allocation_limit, counting it in overflow_mappings. By default the pool waits for
+    /// as long as it takes.
+    pub fn max_backoff_iterations(mut self, iterations: u32) -> Self {
+        self.max_backoff_iterations = Some(iterations);
+        self
+    }
+
+    /// Leaves this many bytes free at the front of every buffer, for Parts to reclaim later. 0 (no
+    /// headroom) by default.
+    pub fn headroom(mut self, headroom: usize) -> Self {
+        self.headroom = headroom;
+        self
+    }
+
+    pub fn build(self) -> Result<GlobalMemPoolSettings, SettingsError> {
+        if !self.buffer_bytes.is_power_of_two() {
+            return Err(SettingsError::BufferNotPowerOfTwo(self.buffer_bytes));
+        }
+        if self.buffer_bytes <= std::mem::size_of::<u32>() {
+            return Err(SettingsError::BufferTooSmall(self.buffer_bytes));
+        }
+        if self.buffers_per_page == 0 {
+            return Err(SettingsError::NoBuffersPerPage);
+        }
+        let max_buffers_per_page = self.max_buffers_per_page.unwrap_or(self.buffers_per_page);
+        if max_buffers_per_page < self.buffers_per_page {
+            return Err(SettingsError::MaxBuffersPerPageTooSmall);
+        }
+        if self.concurrent_allocation_limit == 0 {
+            return Err(SettingsError::NoConcurrentAllocations);
+        }
+        match max_buffers_per_page.checked_mul(self.buffer_bytes) {
+            Some(len) if len <= isize::MAX as usize => {}
+            _ => return Err(SettingsError::PageTooLarge),
+        }
+        if self.headroom >= self.buffer_bytes - std::mem::size_of::<u32>() {
+            return Err(SettingsError::HeadroomTooLarge(self.headroom));
+        }
+
+        Ok(GlobalMemPoolSettings {
+            buf_size: self.buffer_bytes.trailing_zeros() as usize,
+            page_entries: self.buffers_per_page,
+            max_page_entries: self.max_buffers_per_page,
+            concurrent_allocation_limit: self.concurrent_allocation_limit,
+            max_backoff_iterations: self.max_backoff_iterations,
+            headroom: self.headroom,
+        })
+    }
 }
 
 struct Page {
@@ -50,24 +190,77 @@ pub struct Part<'a> {
     data: Slice,
 }
 
+// A Part may be handed to another thread - the refcount sentinel shared between sibling Parts is
+// atomic, and the slice a Part covers is never covered by any other Part.
+unsafe impl<'a> Send for Part<'a> {}
+unsafe impl<'a> Sync for Part<'a> {}
+
 impl<'a> Part<'a> {
-    unsafe fn rc(&self) -> *mut u32 {
-        self.parent_slice.offset(self.global_mempool.realsize) as *mut u32
+    unsafe fn rc(&self) -> &AtomicU32 {
+        &*(self.parent_slice.offset(self.global_mempool.realsize) as *const AtomicU32)
     }
 
     unsafe fn increment_rc(&self) {
-        *self.rc() += 1;
+        self.rc().fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of Parts (this one included) sharing the slice this Part was split from
+    pub fn ref_count(&self) -> u32 {
+        unsafe { self.rc().load(Ordering::Acquire) }
+    }
+
+    /// How many bytes of the slice come before this Part - the pool's headroom, plus anything
+    /// advanced past since
+    pub fn headroom(&self) -> usize {
+        self.data.ptr as usize - self.parent_slice as usize
+    }
+
+    /// Grows this Part back over n bytes of headroom in front of it, so that they can be written
+    /// to. This is only possible while no other Part shares the slice, as the headroom could
+    /// otherwise belong to one of them. Returns false, leaving the Part alone, if it isn't.
+    pub fn reclaim_headroom(&mut self, n: usize) -> bool {
+        if n > self.headroom() || self.ref_count() != 1 {
+            return false;
+        }
+
+        unsafe {
+            self.data.ptr = self.data.ptr.sub(n);
+        }
+        self.data.len += n;
+        true
+    }
+
+    /// Identifies the slice this Part was split from - Parts which share a slice share an id
+    #[cfg(debug_assertions)]
+    pub fn parent_id(&self) -> usize {
+        self.parent_slice as usize
+    }
+
+    /// Gives this Part up right away, the same as dropping it would. Returns true if it was the
+    /// last Part sharing its slice, so the slice went back to the pool (on this thread's cache if
+    /// there is room) and is ready to be allocated again.
+    pub fn release(self) -> bool {
+        let part = std::mem::ManuallyDrop::new(self);
+        unsafe { part.release_ref() }
+    }
+
+    // Must only be called once per Part, as it gives up this Part's count on the slice
+    unsafe fn release_ref(&self) -> bool {
+        // AcqRel so that every write made through a sibling Part happens before the slice is
+        // handed back out by the pool
+        if self.rc().fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.global_mempool.reclaim(self.parent_slice);
+            true
+        } else {
+            false
+        }
     }
 }
 
 impl<'a> Drop for Part<'a> {
     fn drop(&mut self) {
         unsafe {
-            let rc = self.rc();
-            if *rc == 1 {
-                self.global_mempool.reclaim(self.parent_slice);
-            }
-            *rc -= 1;
+            self.release_ref();
         }
     }
 }
@@ -149,7 +342,9 @@ impl<'a> DirectBuf for Part<'a> {
     fn split_to(&mut self, at: usize) -> Self {
         let old_ptr = self.data.ptr;
 
-        // Rust will guard this operation from overflowing, protecting the unsafe below.
+        // Rust will guard this operation from overflowing, protecting the unsafe below. Splitting
+        // at the full length leaves self empty, pointing just past the end, but it keeps its count
+        // on the slice and gives it up on drop the same as any other Part.
         self.data.len -= at;
         unsafe {
             self.data.ptr = self.data.ptr.add(at);
@@ -166,6 +361,10 @@ impl<'a> DirectBuf for Part<'a> {
             },
         }
     }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        Some(self.parent_slice)
+    }
 }
 
 impl<'a> DirectBufMut for Part<'a> {
@@ -174,6 +373,151 @@ impl<'a> DirectBufMut for Part<'a> {
     }
 }
 
+/// A page which is either pooled, owned or shared, so that Parts and Bytes handed over from
+/// elsewhere can be held in the same Multibytes. Bytes can't be written to in place, so writing into
+/// a Shared page first copies it into an Owned one.
+pub enum AnyBuf<'a> {
+    Pooled(Part<'a>),
+    Owned(bytes::BytesMut),
+    Shared(bytes::Bytes),
+}
+
+impl<'a> AnyBuf<'a> {
+    // Copies a Shared page into an Owned one, so that it can be written to
+    fn make_mut(&mut self) {
+        if let AnyBuf::Shared(b) = self {
+            *self = AnyBuf::Owned(bytes::BytesMut::from(&b[..]));
+        }
+    }
+}
+
+impl<'a> From<Part<'a>> for AnyBuf<'a> {
+    fn from(p: Part<'a>) -> AnyBuf<'a> {
+        AnyBuf::Pooled(p)
+    }
+}
+
+impl<'a> From<bytes::BytesMut> for AnyBuf<'a> {
+    fn from(b: bytes::BytesMut) -> AnyBuf<'a> {
+        AnyBuf::Owned(b)
+    }
+}
+
+impl<'a> From<bytes::Bytes> for AnyBuf<'a> {
+    fn from(b: bytes::Bytes) -> AnyBuf<'a> {
+        AnyBuf::Shared(b)
+    }
+}
+
+impl<'a> AsRef<[u8]> for AnyBuf<'a> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            AnyBuf::Pooled(p) => p.as_ref(),
+            AnyBuf::Owned(b) => b.as_ref(),
+            AnyBuf::Shared(b) => b.as_ref(),
+        }
+    }
+}
+
+impl<'a> AsMut<[u8]> for AnyBuf<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.make_mut();
+        match self {
+            AnyBuf::Pooled(p) => p.as_mut(),
+            AnyBuf::Owned(b) => b.as_mut(),
+            AnyBuf::Shared(_) => unreachable!("shared page was not made mutable"),
+        }
+    }
+}
+
+impl<'a> bytes::Buf for AnyBuf<'a> {
+    fn remaining(&self) -> usize {
+        match self {
+            AnyBuf::Pooled(p) => p.remaining(),
+            AnyBuf::Owned(b) => b.remaining(),
+            AnyBuf::Shared(b) => b.remaining(),
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        match self {
+            AnyBuf::Pooled(p) => p.advance(cnt),
+            AnyBuf::Owned(b) => b.advance(cnt),
+            AnyBuf::Shared(b) => b.advance(cnt),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+impl<'a> bytes::BufMut for AnyBuf<'a> {
+    fn remaining_mut(&self) -> usize {
+        match self {
+            AnyBuf::Pooled(p) => p.remaining_mut(),
+            AnyBuf::Owned(b) => b.remaining_mut(),
+            // Writing would copy it into a BytesMut first, which grows as it is written to
+            AnyBuf::Shared(b) => usize::MAX - b.len(),
+        }
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.make_mut();
+        match self {
+            AnyBuf::Pooled(p) => p.advance_mut(cnt),
+            AnyBuf::Owned(b) => b.advance_mut(cnt),
+            AnyBuf::Shared(_) => unreachable!("shared page was not made mutable"),
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        self.make_mut();
+        match self {
+            AnyBuf::Pooled(p) => p.bytes_mut(),
+            AnyBuf::Owned(b) => b.bytes_mut(),
+            AnyBuf::Shared(_) => unreachable!("shared page was not made mutable"),
+        }
+    }
+}
+
+impl<'a> DirectBuf for AnyBuf<'a> {
+    fn truncate(&mut self, len: usize) {
+        match self {
+            AnyBuf::Pooled(p) => DirectBuf::truncate(p, len),
+            AnyBuf::Owned(b) => b.truncate(len),
+            AnyBuf::Shared(b) => b.truncate(len),
+        }
+    }
+
+    fn split_to(&mut self, at: usize) -> Self {
+        match self {
+            AnyBuf::Pooled(p) => AnyBuf::Pooled(DirectBuf::split_to(p, at)),
+            AnyBuf::Owned(b) => AnyBuf::Owned(b.split_to(at)),
+            AnyBuf::Shared(b) => AnyBuf::Shared(b.split_to(at)),
+        }
+    }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        match self {
+            AnyBuf::Pooled(p) => p.base_ptr(),
+            AnyBuf::Owned(b) => DirectBuf::base_ptr(b),
+            AnyBuf::Shared(b) => DirectBuf::base_ptr(b),
+        }
+    }
+}
+
+impl<'a> DirectBufMut for AnyBuf<'a> {
+    unsafe fn bytes_mut_assume_init(&mut self) -> &mut [u8] {
+        self.make_mut();
+        match self {
+            AnyBuf::Pooled(p) => p.bytes_mut_assume_init(),
+            AnyBuf::Owned(b) => b.bytes_mut_assume_init(),
+            AnyBuf::Shared(_) => unreachable!("shared page was not made mutable"),
+        }
+    }
+}
+
 pub struct TLMemPool {
     pub cache: Vec<*mut u8>,
 }
@@ -194,29 +538,210 @@ impl<'a> BlockAllocator<'a, bytes::BytesMut> for SystemMemPool {
     }
 }
 
+/// An allocator which hands out fixed size BytesMut buffers and keeps count of how many have been
+/// allocated and freed. Buffers produced by splitting a CountedBuf are counted as allocations, so
+/// once every buffer has been dropped `allocations() == frees()` should hold.
+pub struct CountingMemPool {
+    pub buf_size: usize,
+    allocs: Cell<usize>,
+    frees: Cell<usize>,
+}
+
+impl CountingMemPool {
+    pub fn new(buf_size: usize) -> CountingMemPool {
+        CountingMemPool {
+            buf_size,
+            allocs: Cell::new(0),
+            frees: Cell::new(0),
+        }
+    }
+
+    /// The number of buffers this pool has handed out, including buffers split off of them
+    pub fn allocations(&self) -> usize {
+        self.allocs.get()
+    }
+
+    /// The number of buffers handed out by this pool which have since been dropped
+    pub fn frees(&self) -> usize {
+        self.frees.get()
+    }
+
+    /// The number of buffers handed out by this pool which are still alive
+    pub fn outstanding(&self) -> usize {
+        self.allocs.get() - self.frees.get()
+    }
+}
+
+pub struct CountedBuf<'a> {
+    pool: &'a CountingMemPool,
+    b: bytes::BytesMut,
+}
+
+impl<'a> Drop for CountedBuf<'a> {
+    fn drop(&mut self) {
+        self.pool.frees.update(|v| v + 1);
+    }
+}
+
+impl<'a> Deref for CountedBuf<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.b
+    }
+}
+
+impl<'a> DerefMut for CountedBuf<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.b
+    }
+}
+
+impl<'a> AsRef<[u8]> for CountedBuf<'a> {
+    fn as_ref(&self) -> &[u8] {
+        &self.b
+    }
+}
+
+impl<'a> AsMut<[u8]> for CountedBuf<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.b
+    }
+}
+
+impl<'a> bytes::Buf for CountedBuf<'a> {
+    fn remaining(&self) -> usize {
+        self.b.remaining()
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.b.advance(cnt)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.b.bytes()
+    }
+}
+
+impl<'a> bytes::BufMut for CountedBuf<'a> {
+    fn remaining_mut(&self) -> usize {
+        self.b.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.b.advance_mut(cnt)
+    }
+
+    fn bytes_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        self.b.bytes_mut()
+    }
+}
+
+impl<'a> DirectBuf for CountedBuf<'a> {
+    fn truncate(&mut self, len: usize) {
+        self.b.truncate(len)
+    }
+
+    fn split_to(&mut self, at: usize) -> Self {
+        let b = self.b.split_to(at);
+        // The split off half will be dropped on its own, so it has to be counted on its own
+        self.pool.allocs.update(|v| v + 1);
+        CountedBuf { pool: self.pool, b }
+    }
+
+    fn base_ptr(&self) -> Option<*const u8> {
+        self.b.base_ptr()
+    }
+}
+
+impl<'a> DirectBufMut for CountedBuf<'a> {
+    unsafe fn bytes_mut_assume_init(&mut self) -> &mut [u8] {
+        self.b.bytes_mut_assume_init()
+    }
+}
+
+impl<'a> BlockAllocator<'a, CountedBuf<'a>> for CountingMemPool {
+    fn allocate(&'a self) -> CountedBuf<'a> {
+        let mut b = bytes::BytesMut::with_capacity(1 << self.buf_size);
+        unsafe { b.set_len(1 << self.buf_size) };
+        self.allocs.update(|v| v + 1);
+        CountedBuf { pool: self, b }
+    }
+}
+
+/// A pool of fixed size buffers carved out of large anonymous mappings.
+///
+/// A GlobalMemPool may be shared between threads, and Parts allocated from it may be moved between
+/// threads freely. Each thread keeps its own cache of free slices through the thread local the pool
+/// was created with, falling back to the shared global queue when that cache is empty or full.
 pub struct GlobalMemPool {
     memory: SegQueue<*mut u8>,
     lk: &'static std::thread::LocalKey<RefCell<TLMemPool>>,
     settings: GlobalMemPoolSettings,
     realsize: isize,
     allocs: AtomicU64,
+    next_page_entries: AtomicUsize,
+    mappings: AtomicU64,
+    overflow_mappings: AtomicU64,
+    failed_mappings: AtomicU64,
 }
 
+// The raw pointers held by the pool are unowned slices of mappings which live for the lifetime of
+// the program, and all shared state is either behind the SegQueue, atomic, or thread local.
+unsafe impl Send for GlobalMemPool {}
+unsafe impl Sync for GlobalMemPool {}
+
 impl GlobalMemPool {
     /// Creates a new GlobalMemPool with the given settings
     pub fn new(
         global_tlmp_ref: &'static std::thread::LocalKey<RefCell<TLMemPool>>,
         settings: GlobalMemPoolSettings,
     ) -> GlobalMemPool {
+        assert!(
+            settings.buf_size < 64,
+            "buf_size of {} is too large for the address space",
+            settings.buf_size
+        );
+        // Every buffer ends in a u32 refcount sentinel, so it has to be bigger than one
+        assert!(
+            (1usize << settings.buf_size) > std::mem::size_of::<u32>(),
+            "buf_size of {} is too small to hold the refcount sentinel",
+            settings.buf_size
+        );
+        assert!(
+            settings.headroom < (1 << settings.buf_size) - std::mem::size_of::<u32>(),
+            "headroom of {} leaves no room for data",
+            settings.headroom
+        );
+
         GlobalMemPool {
             memory: SegQueue::new(),
             lk: global_tlmp_ref,
             allocs: AtomicU64::new(0),
+            next_page_entries: AtomicUsize::new(settings.page_entries),
+            mappings: AtomicU64::new(0),
+            overflow_mappings: AtomicU64::new(0),
+            failed_mappings: AtomicU64::new(0),
             realsize: ((1 << settings.buf_size) - std::mem::size_of::<u32>()) as isize,
             settings,
         }
     }
 
+    /// The number of pages which have been mapped so far
+    pub fn mappings(&self) -> u64 {
+        self.mappings.load(Ordering::Relaxed)
+    }
+
+    /// The number of pages allocate mapped past the concurrent_allocation_limit, having given up
+    /// on waiting for other threads after max_backoff_iterations. These are included in mappings.
+    pub fn overflow_mappings(&self) -> u64 {
+        self.overflow_mappings.load(Ordering::Relaxed)
+    }
+
+    /// The number of times mapping a page has failed
+    pub fn failed_mappings(&self) -> u64 {
+        self.failed_mappings.load(Ordering::Relaxed)
+    }
+
     fn reclaim(&self, memory: *mut u8) {
         self.lk.with(|tlmp_rc| {
             unsafe {
@@ -233,78 +758,179 @@ impl GlobalMemPool {
         });
     }
 
-    fn allocate_global(&self) -> *mut u8 {
+    // Takes a slice from the global queue, mapping a new page if it is empty. None if other threads
+    // are already mapping as many pages at once as they are allowed to.
+    fn try_allocate_global(&self) -> Option<std::io::Result<*mut u8>> {
+        if let Ok(slice) = self.memory.pop() {
+            return Some(Ok(slice));
+        }
+
+        // Try to allocate
+        let previous_allocs = self.allocs.fetch_add(1, Ordering::AcqRel);
+        if previous_allocs > self.settings.concurrent_allocation_limit - 1 {
+            // We are already allocating maximum pages, back off
+            self.allocs.fetch_sub(1, Ordering::Release);
+            return None;
+        }
+
+        let slice = self.map_page();
+        self.allocs.fetch_sub(1, Ordering::Release);
+        Some(slice)
+    }
+
+    // Maps a new page, handing back its first slice and queueing up the rest
+    fn map_page(&self) -> std::io::Result<*mut u8> {
+        // perform a new allocation. This can legitimately fail under load (out of memory, or out
+        // of mappings), which is left to the caller to deal with
+        let entries = self.next_page_entries.load(Ordering::Relaxed);
+        // Settings which didn't come through the builder may ask for more than could ever be mapped,
+        // and shifting that out would quietly wrap around to a much smaller page
+        let len = match entries.checked_mul(1 << self.settings.buf_size) {
+            Some(len) if len <= isize::MAX as usize => len,
+            _ => {
+                self.failed_mappings.fetch_add(1, Ordering::Relaxed);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "a page of {} buffers of {} bytes is too large",
+                        entries,
+                        1usize << self.settings.buf_size
+                    ),
+                ));
+            }
+        };
+        let mm = match memmap::MmapMut::map_anon(len) {
+            Ok(mm) => mm,
+            Err(e) => {
+                self.failed_mappings.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+
+        let page = Box::into_raw(Box::new(Page { m: mm }));
+
+        // Now you may asking, woah there cowboy. Thats some pretty unsafe bullshit you are pulling
+        // here. And I would agree. Unfortuantely the rust compiler has lost to the will of me -
+        // this should work, as the slice will be static in memory no matter where the structures
+        // move (as is intended).
+        let base_ptr = unsafe { page.as_ref().unwrap() }.m.deref().as_ptr() as *mut u8;
+
+        // Racing allocators may both see the same size - either way, the next page only ever grows
+        let max_entries = self
+            .settings
+            .max_page_entries
+            .unwrap_or(self.settings.page_entries);
+        let grown = std::cmp::min(entries.saturating_mul(2), max_entries);
+        self.next_page_entries.fetch_max(grown, Ordering::Relaxed);
+        self.mappings.fetch_add(1, Ordering::Relaxed);
+
+        for itr in 1..entries {
+            let ptr = unsafe { base_ptr.add(itr << self.settings.buf_size) };
+            self.memory.push(ptr);
+        }
+
+        Ok(base_ptr)
+    }
+
+    fn allocate_global(&self) -> std::io::Result<*mut u8> {
         let backoff = Backoff::new();
+        let mut iterations = 0;
         loop {
-            match self.memory.pop() {
-                Ok(slice) => return slice,
-                Err(_) => {
-                    // Try to allocate
-                    let previous_allocs = self.allocs.fetch_add(1, Ordering::AcqRel);
-                    if previous_allocs <= self.settings.concurrent_allocation_limit - 1 {
-                        // perform a new allocation
-                        // TODO: This should fail more.... gracefully? Blowing up the program isn't
-                        // exactly... nice?
-                        let mm = memmap::MmapMut::map_anon(
-                            self.settings.page_entries << self.settings.buf_size,
-                        )
-                        .unwrap();
-
-                        let page = Box::into_raw(Box::new(Page { m: mm }));
-
-                        // Now you may asking, woah there cowboy. Thats some pretty unsafe bullshit
-                        // you are pulling here. And I would agree. Unfortuantely the rust compiler
-                        // has lost to the will of me - this should work, as the slice will be
-                        // static in memory no matter where the structures move (as is intended).
-                        let base_ptr =
-                            unsafe { page.as_ref().unwrap() }.m.deref().as_ptr() as *mut u8;
-
-                        for itr in 1..self.settings.page_entries {
-                            let ptr = unsafe { base_ptr.add(itr << self.settings.buf_size) };
-                            self.memory.push(ptr);
-                        }
-
-                        self.allocs.fetch_sub(1, Ordering::Release);
-
-                        return base_ptr;
-                    } else {
-                        // We are already allocating maximum pages, back off
-                        self.allocs.fetch_sub(1, Ordering::Release);
-
-                        backoff.spin();
-                        backoff.snooze();
+            match self.try_allocate_global() {
+                Some(r) => return r,
+                None => {
+                    if Some(iterations) == self.settings.max_backoff_iterations {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "gave up waiting for other threads to map pages",
+                        ));
                     }
-                    continue;
+                    iterations += 1;
+                    backoff.spin();
+                    backoff.snooze();
                 }
             }
         }
     }
-}
 
-impl<'a> BlockAllocator<'a, Part<'a>> for GlobalMemPool {
-    /// Allocates a new Part
-    fn allocate(&self) -> Part {
-        let slice = self
-            .lk
-            .with(|tlmp| unsafe { (*tlmp.as_ptr()).cache.pop() })
-            .unwrap_or_else(|| self.allocate_global());
+    fn local_slice(&self) -> Option<*mut u8> {
+        self.lk.with(|tlmp| unsafe { (*tlmp.as_ptr()).cache.pop() })
+    }
 
+    fn part_of(&self, slice: *mut u8) -> Part {
         // There is a special sentienl at the tail end of every slice which acts as
         // the refcount value
         unsafe {
-            let refcount_ptr = slice.offset(self.realsize as isize) as *mut u32;
-            *refcount_ptr = 1;
+            let refcount_ptr = slice.offset(self.realsize as isize) as *const AtomicU32;
+            (*refcount_ptr).store(1, Ordering::Relaxed);
         }
 
         Part {
             global_mempool: self,
             parent_slice: slice,
             data: Slice {
-                ptr: slice,
-                len: self.realsize as usize,
+                ptr: unsafe { slice.add(self.settings.headroom) },
+                len: self.realsize as usize - self.settings.headroom,
             },
         }
     }
+
+    /// Allocates a new Part, failing if the pool is out of slices and can't map any more, or if it
+    /// backed off more than max_backoff_iterations times waiting for other threads to map pages.
+    pub fn try_allocate(&self) -> std::io::Result<Part> {
+        let slice = match self.local_slice() {
+            Some(slice) => slice,
+            None => self.allocate_global()?,
+        };
+        Ok(self.part_of(slice))
+    }
+
+    /// Allocates a new Part like allocate does, but for use from async tasks. Rather than spinning
+    /// on the current thread whenever a Part can't be had straight away, this yields to the
+    /// runtime so that other tasks (perhaps ones holding on to Parts) can get on with things.
+    /// Only waiting on other threads is retried - an error mapping a page is returned as is.
+    pub async fn allocate_async(&self) -> std::io::Result<Part> {
+        loop {
+            let slice = match self.local_slice() {
+                Some(slice) => Some(Ok(slice)),
+                None => self.try_allocate_global(),
+            };
+            match slice {
+                Some(slice) => return Ok(self.part_of(slice?)),
+                None => tokio::task::yield_now().await,
+            }
+        }
+    }
+}
+
+impl<'a> BlockAllocator<'a, Part<'a>> for GlobalMemPool {
+    /// Allocates a new Part. If waiting on other threads to map pages takes too long, this maps a
+    /// page of its own regardless, counted by overflow_mappings. If a page can't be mapped, this
+    /// keeps waiting until a Part is returned to the pool or a later mapping succeeds - use
+    /// try_allocate to handle the failure instead. Settings asking for a page too large to ever
+    /// be mapped panic.
+    fn allocate(&self) -> Part {
+        let backoff = Backoff::new();
+        loop {
+            let err = match self.try_allocate() {
+                Ok(p) => return p,
+                // Waiting on the other threads took too long, so map a page of our own regardless
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => match self.map_page() {
+                    Ok(slice) => {
+                        self.overflow_mappings.fetch_add(1, Ordering::Relaxed);
+                        return self.part_of(slice);
+                    }
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            // Waiting won't make a page of this size any more mappable
+            if err.kind() == std::io::ErrorKind::InvalidInput {
+                panic!("could not map a page: {}", err);
+            }
+            backoff.snooze();
+        }
+    }
 }
 
 #[macro_use]
@@ -332,6 +958,7 @@ mod tests {
                 buf_size: 12,
                 concurrent_allocation_limit: 1,
                 page_entries: 64,
+                ..Default::default()
             },
         );
 
@@ -341,6 +968,558 @@ mod tests {
         }
     }
 
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    global_mempool_tlmp!(cross_thread_pool, 64);
+    #[test]
+    fn cross_thread() {
+        assert_send_sync::<GlobalMemPool>();
+        assert_send_sync::<Part<'static>>();
+
+        let allocator = GlobalMemPool::new(
+            &cross_thread_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                concurrent_allocation_limit: 1,
+                page_entries: 64,
+                ..Default::default()
+            },
+        );
+
+        crossbeam_utils::thread::scope(|s| {
+            for t in 0..4 {
+                let allocator = &allocator;
+                s.spawn(move |s| {
+                    for i in 0..1000 {
+                        let mut buffer = allocator.allocate();
+                        let mut front = buffer.split_to(16);
+                        front[0] = t as u8;
+                        buffer[0] = i as u8;
+
+                        // Drop the halves on different threads
+                        s.spawn(move |_| drop(front)).join().unwrap();
+                        drop(buffer);
+                    }
+                });
+            }
+        })
+        .unwrap();
+    }
+
+    global_mempool_tlmp!(ref_count_pool, 64);
+    #[test]
+    fn ref_count() {
+        let allocator = GlobalMemPool::new(
+            &ref_count_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                concurrent_allocation_limit: 1,
+                page_entries: 64,
+                ..Default::default()
+            },
+        );
+
+        let mut buffer = allocator.allocate();
+        let other = allocator.allocate();
+        assert_eq!(buffer.ref_count(), 1);
+
+        let a = buffer.split_to(16);
+        let b = buffer.split_to(16);
+        assert_eq!(buffer.ref_count(), 3);
+        assert_eq!(a.ref_count(), 3);
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(a.parent_id(), buffer.parent_id());
+            assert_eq!(b.parent_id(), buffer.parent_id());
+            assert_ne!(other.parent_id(), buffer.parent_id());
+        }
+
+        drop(a);
+        assert_eq!(buffer.ref_count(), 2);
+        assert_eq!(b.ref_count(), 2);
+        assert_eq!(other.ref_count(), 1);
+    }
+
+    #[test]
+    fn settings_builder() {
+        let settings = GlobalMemPoolSettings::builder()
+            .buffer_bytes(8192)
+            .buffers_per_page(32)
+            .concurrent_allocation_limit(2)
+            .max_backoff_iterations(16)
+            .build()
+            .unwrap();
+        assert_eq!(settings.buf_size, 13);
+        assert_eq!(settings.page_entries, 32);
+        assert_eq!(settings.concurrent_allocation_limit, 2);
+        assert_eq!(settings.max_backoff_iterations, Some(16));
+
+        let settings = GlobalMemPoolSettings::builder().build().unwrap();
+        assert_eq!(settings.buf_size, 12);
+        assert_eq!(settings.page_entries, 64);
+        assert_eq!(settings.max_page_entries, None);
+
+        let err = |b: GlobalMemPoolSettingsBuilder| b.build().err().unwrap();
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().buffer_bytes(3000)),
+            SettingsError::BufferNotPowerOfTwo(3000)
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().buffer_bytes(4)),
+            SettingsError::BufferTooSmall(4)
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().buffers_per_page(0)),
+            SettingsError::NoBuffersPerPage
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder()
+                .buffers_per_page(32)
+                .max_buffers_per_page(16)),
+            SettingsError::MaxBuffersPerPageTooSmall
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().max_buffers_per_page(1 << 60)),
+            SettingsError::PageTooLarge
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().concurrent_allocation_limit(0)),
+            SettingsError::NoConcurrentAllocations
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder().buffers_per_page(1 << 60)),
+            SettingsError::PageTooLarge
+        );
+        assert_eq!(
+            err(GlobalMemPoolSettings::builder()
+                .buffer_bytes(64)
+                .headroom(60)),
+            SettingsError::HeadroomTooLarge(60)
+        );
+    }
+
+    global_mempool_tlmp!(tiny_buf_pool, 64);
+    #[test]
+    #[should_panic(expected = "too small to hold the refcount sentinel")]
+    fn tiny_buf_size() {
+        GlobalMemPool::new(
+            &tiny_buf_pool,
+            GlobalMemPoolSettings {
+                buf_size: 1,
+                concurrent_allocation_limit: 1,
+                page_entries: 64,
+                ..Default::default()
+            },
+        );
+    }
+
+    global_mempool_tlmp!(huge_buf_pool, 0);
+    #[test]
+    #[should_panic(expected = "too large for the address space")]
+    fn huge_buf_size() {
+        GlobalMemPool::new(
+            &huge_buf_pool,
+            GlobalMemPoolSettings {
+                buf_size: 64,
+                page_entries: 1,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        );
+    }
+
+    global_mempool_tlmp!(map_failure_pool, 64);
+    #[test]
+    fn map_failure() {
+        let allocator = GlobalMemPool::new(
+            &map_failure_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                concurrent_allocation_limit: 1,
+                // Far more than any address space can hold
+                page_entries: 1 << 50,
+                ..Default::default()
+            },
+        );
+
+        assert!(allocator.try_allocate().is_err());
+        // Failing doesn't hold up later attempts
+        assert!(allocator.try_allocate().is_err());
+        assert_eq!(allocator.failed_mappings(), 2);
+    }
+
+    global_mempool_tlmp!(allocate_map_failure_pool, 64);
+    #[test]
+    #[should_panic(expected = "could not map a page")]
+    fn allocate_map_failure() {
+        let allocator = GlobalMemPool::new(
+            &allocate_map_failure_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                concurrent_allocation_limit: 1,
+                // Too large to even ask for
+                page_entries: 1 << 52,
+                ..Default::default()
+            },
+        );
+
+        // Rather than spinning on a mapping that will never work
+        allocator.allocate();
+    }
+
+    global_mempool_tlmp!(allocate_waits_on_map_failure_pool, 0);
+    #[test]
+    fn allocate_waits_on_map_failure() {
+        let pool = GlobalMemPool::new(
+            &allocate_waits_on_map_failure_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                concurrent_allocation_limit: 1,
+                page_entries: 1,
+                ..Default::default()
+            },
+        );
+
+        let held = pool.allocate();
+        // Every page after the first is more than the address space can hold, so the only way to
+        // get another Part is for this one to be returned
+        pool.next_page_entries.store(1 << 50, Ordering::Relaxed);
+
+        let freed = AtomicU64::new(0);
+        crossbeam_utils::thread::scope(|s| {
+            s.spawn(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                freed.store(1, Ordering::Release);
+                drop(held);
+            });
+
+            let part = pool.allocate();
+            assert_eq!(freed.load(Ordering::Acquire), 1);
+            drop(part);
+        })
+        .unwrap();
+        assert_eq!(pool.mappings(), 1);
+        assert!(pool.failed_mappings() > 0);
+    }
+
+    // Allocates a burst of buffers, holding onto all of them, and returns how many pages it took
+    fn burst_mappings(allocator: &GlobalMemPool, n: usize) -> u64 {
+        let held: Vec<Part> = (0..n).map(|_| allocator.allocate()).collect();
+        test::black_box(&held);
+        allocator.mappings()
+    }
+
+    global_mempool_tlmp!(page_growth_pool, 0);
+    #[test]
+    fn page_growth() {
+        let fixed = GlobalMemPool::new(
+            &page_growth_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(16)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(burst_mappings(&fixed, 1000), 63);
+
+        // 16 + 32 + 64 + 128 + 256 + 256 + 256 covers 1000 buffers
+        let growing = GlobalMemPool::new(
+            &page_growth_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(16)
+                .max_buffers_per_page(256)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(burst_mappings(&growing, 1000), 7);
+    }
+
+    global_mempool_tlmp!(allocate_async_pool, 4);
+    #[test]
+    fn allocate_async() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        let pool: &'static GlobalMemPool = Box::leak(Box::new(GlobalMemPool::new(
+            &allocate_async_pool,
+            GlobalMemPoolSettings {
+                buf_size: 12,
+                page_entries: 1,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        )));
+
+        rt.block_on(async move {
+            let held = pool.allocate_async().await.unwrap();
+            // Pretend another thread is busy mapping, so the only way to get a Part is for one to
+            // be returned
+            pool.allocs.store(1, Ordering::Release);
+
+            let freed = std::sync::Arc::new(AtomicU64::new(0));
+            let freer = freed.clone();
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    tokio::task::yield_now().await;
+                }
+                freer.store(1, Ordering::Release);
+                drop(held);
+            });
+
+            let part = pool.allocate_async().await.unwrap();
+            assert_eq!(freed.load(Ordering::Acquire), 1);
+            assert_eq!(pool.mappings(), 1);
+            drop(part);
+        });
+    }
+
+    global_mempool_tlmp!(backoff_limit_pool, 0);
+    #[test]
+    fn backoff_limit() {
+        let pool = GlobalMemPool::new(
+            &backoff_limit_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(1)
+                .max_backoff_iterations(8)
+                .build()
+                .unwrap(),
+        );
+
+        let held = pool.try_allocate().unwrap();
+        assert_eq!(pool.mappings(), 1);
+        // Pretend another thread is busy mapping and never finishes
+        pool.allocs.store(1, Ordering::Release);
+
+        let err = pool.try_allocate().err().expect("should have given up");
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(pool.mappings(), 1);
+
+        // allocate can't fail, so it maps past the limit instead
+        let forced = pool.allocate();
+        assert_eq!(pool.mappings(), 2);
+        assert_eq!(pool.overflow_mappings(), 1);
+        drop(forced);
+        drop(held);
+    }
+
+    global_mempool_tlmp!(oversized_page_pool, 0);
+    #[test]
+    fn oversized_page() {
+        // 2^40 byte buffers, 2^30 to a page
+        let pool = GlobalMemPool::new(
+            &oversized_page_pool,
+            GlobalMemPoolSettings {
+                buf_size: 40,
+                page_entries: 1 << 30,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        );
+
+        let err = pool.try_allocate().err().expect("should not have mapped");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(pool.mappings(), 0);
+        // Nothing was left half done, so the pool can still be tried again
+        assert_eq!(pool.allocs.load(Ordering::Acquire), 0);
+
+        // Nor does allocate_async keep waiting for a page that could never be mapped
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let err = rt
+            .block_on(pool.allocate_async())
+            .err()
+            .expect("should not have mapped");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    global_mempool_tlmp!(release_pool, 4);
+    #[test]
+    fn release() {
+        let pool = GlobalMemPool::new(
+            &release_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(4)
+                .build()
+                .unwrap(),
+        );
+
+        let mut part = pool.allocate();
+        let slice = part.base_ptr();
+        let front = part.split_to(16);
+        // Its sibling still holds the slice
+        assert!(!front.release());
+        assert_eq!(part.ref_count(), 1);
+        assert!(part.release());
+
+        // Straight back out of this thread's cache
+        let again = pool.allocate();
+        assert_eq!(again.base_ptr(), slice);
+        assert_eq!(again.ref_count(), 1);
+    }
+
+    global_mempool_tlmp!(split_whole_pool, 4);
+    #[test]
+    fn split_whole() {
+        let pool = GlobalMemPool::new(
+            &split_whole_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(4)
+                .build()
+                .unwrap(),
+        );
+
+        let mut rest = pool.allocate();
+        let slice = rest.base_ptr();
+        let len = rest.len();
+        let whole = rest.split_to(len);
+        assert_eq!(whole.len(), len);
+        assert_eq!(rest.len(), 0);
+        assert_eq!(rest.ref_count(), 2);
+
+        drop(rest);
+        assert_eq!(whole.ref_count(), 1);
+        drop(whole);
+
+        // Back in this thread's cache, and only the once
+        let cached = split_whole_pool.with(|tlmp| {
+            let tlmp = tlmp.borrow();
+            tlmp.cache
+                .iter()
+                .filter(|&&s| Some(s as *const u8) == slice)
+                .count()
+        });
+        assert_eq!(cached, 1);
+        let again = pool.allocate();
+        assert_eq!(again.base_ptr(), slice);
+        assert_ne!(pool.allocate().base_ptr(), slice);
+    }
+
+    global_mempool_tlmp!(any_buf_pool, 4);
+    #[test]
+    fn any_buf() {
+        use crate::cursor::Multibytes;
+        use bytes::Buf;
+
+        let pool = GlobalMemPool::new(
+            &any_buf_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(4)
+                .build()
+                .unwrap(),
+        );
+
+        let mut part = pool.allocate();
+        part[..3].copy_from_slice(&[1, 2, 3]);
+        DirectBuf::truncate(&mut part, 3);
+
+        let mut mb = Multibytes::from_buf(AnyBuf::from(part));
+        mb.append(AnyBuf::from(bytes::Bytes::from_static(&[4, 5])));
+        mb.append(AnyBuf::from(bytes::Bytes::from_static(&[6])));
+
+        // Read across the boundary between the pooled and shared pages
+        let mut c = mb.cursor();
+        assert!(c.advance(&mb, 2));
+        let mut view = mb.cursor_view(c);
+        assert_eq!(view.get_u8(), 3);
+        assert_eq!(view.get_u16(), 0x0405);
+        assert_eq!(view.get_u8(), 6);
+        assert_eq!(view.remaining(), 0);
+
+        let mut front = mb.split_to(&c);
+        assert_eq!(front.to_bytes(), &[1, 2][..]);
+        assert_eq!(mb.to_bytes(), &[3, 4, 5, 6][..]);
+    }
+
+    global_mempool_tlmp!(any_buf_mut_pool, 4);
+    #[test]
+    fn any_buf_mut() {
+        let pool = GlobalMemPool::new(
+            &any_buf_mut_pool,
+            GlobalMemPoolSettings::builder()
+                .buffers_per_page(4)
+                .build()
+                .unwrap(),
+        );
+
+        fn fill<T: DirectBufMut>(b: &mut T, v: u8) {
+            for x in unsafe { b.bytes_mut_assume_init() }.iter_mut() {
+                *x = v;
+            }
+        }
+
+        // Pooled pages are written in place
+        let mut pooled = AnyBuf::from(pool.allocate());
+        let slice = pooled.base_ptr();
+        DirectBuf::truncate(&mut pooled, 4);
+        fill(&mut pooled, 1);
+        assert_eq!(pooled.as_ref(), &[1, 1, 1, 1]);
+        assert_eq!(pooled.base_ptr(), slice);
+
+        // Shared pages are copied before they are written, leaving the original alone
+        let original = bytes::Bytes::from(vec![2, 2, 2]);
+        let mut shared = AnyBuf::from(original.clone());
+        shared.as_mut()[0] = 3;
+        assert_eq!(shared.as_ref(), &[3, 2, 2]);
+        assert_eq!(&original[..], &[2, 2, 2]);
+        match shared {
+            AnyBuf::Owned(_) => {}
+            _ => panic!("shared page wasn't copied"),
+        }
+    }
+
+    global_mempool_tlmp!(headroom_pool, 4);
+    #[test]
+    fn headroom() {
+        use crate::framer::Framer;
+        use crate::parser;
+        use bytes::{Buf, BufMut};
+
+        let pool = GlobalMemPool::new(
+            &headroom_pool,
+            GlobalMemPoolSettings::builder()
+                .buffer_bytes(64)
+                .buffers_per_page(4)
+                .headroom(5)
+                .build()
+                .unwrap(),
+        );
+
+        // Read a body into the part
+        let mut part = pool.allocate();
+        assert_eq!(part.headroom(), 5);
+        assert_eq!(part.len(), 64 - 4 - 5);
+        let body = b"a body without a length";
+        (&mut part[..]).put_slice(body);
+        DirectBuf::truncate(&mut part, body.len());
+
+        // Can't reach past the front of the slice
+        assert!(!part.reclaim_headroom(6));
+
+        // Prepend the length in place
+        let len = parser::varint_len(body.len() as i32);
+        assert!(part.reclaim_headroom(len));
+        assert_eq!(part.headroom(), 5 - len);
+        let base = part.as_ptr();
+        assert_eq!(
+            parser::encode_varint(body.len() as i32, &mut &mut part[..]),
+            len
+        );
+
+        let mut framer = Framer::new(64, 1);
+        framer.push_buffer(part);
+        let frame = framer.frame().unwrap();
+        assert_eq!(frame.packet.view().bytes().as_ptr(), base);
+        assert_eq!(frame.into_body().to_bytes(), &body[..]);
+
+        // Not while another part shares the slice
+        let mut back = pool.allocate();
+        let _front = back.split_to(1);
+        assert!(!back.reclaim_headroom(1));
+    }
+
     global_mempool_tlmp!(bench_simple_tl_hot_pool, 64);
     #[bench]
     fn bench_simple_tl_hot(b: &mut Bencher) {
@@ -350,6 +1529,7 @@ mod tests {
                 buf_size: 12,
                 concurrent_allocation_limit: 1,
                 page_entries: 64,
+                ..Default::default()
             },
         );
 
@@ -374,6 +1554,7 @@ mod tests {
                 buf_size: 12,
                 concurrent_allocation_limit: 1,
                 page_entries: 64,
+                ..Default::default()
             },
         );
         for _i in 0..10000 {
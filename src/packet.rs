@@ -15,6 +15,8 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use super::cursor::DirectBufMut;
+use super::mempool::BlockAllocator;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ops::IndexMut;
@@ -72,6 +74,57 @@ impl<T> Drop for FragmentPool<T> {
     }
 }
 
+/// Like FragmentPool, but for buffers - spares are kept on the heap rather than inline, up to a
+/// cap, and handed back out before anything new is allocated. Buffers which don't fit, and any
+/// still held when the pool is dropped, are simply dropped, which hands pooled buffers like Parts
+/// back to the pool they came from rather than freeing them.
+pub struct BufferFragmentPool<T: DirectBufMut> {
+    cap: usize,
+    buf_len: usize,
+    spares: Vec<T>,
+}
+
+impl<T: DirectBufMut> BufferFragmentPool<T> {
+    /// Creates a pool keeping up to cap spare buffers, each of buf_len bytes - the size of a
+    /// buffer fresh from the allocator they are popped in place of.
+    pub fn new(cap: usize, buf_len: usize) -> BufferFragmentPool<T> {
+        BufferFragmentPool {
+            cap,
+            buf_len,
+            spares: Vec::with_capacity(cap),
+        }
+    }
+
+    /// Keeps a buffer for reuse. Only whole buffers of buf_len bytes are kept, so that anything
+    /// popped is as good as a freshly allocated one - a buffer which has been truncated, split or
+    /// read from is dropped instead.
+    pub fn lossy_push(&mut self, b: T) {
+        if self.spares.len() < self.cap && b.remaining() == self.buf_len {
+            self.spares.push(b);
+        }
+    }
+
+    pub fn maybe_pop(&mut self) -> Option<T> {
+        self.spares.pop()
+    }
+
+    /// Reuses a spare buffer if there is one, otherwise allocates a new one
+    pub fn pop_or_allocate<'a, A: BlockAllocator<'a, T>>(&mut self, alloc: &'a A) -> T {
+        match self.spares.pop() {
+            Some(b) => b,
+            None => alloc.allocate(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.spares.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spares.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod fragment_pool_tests {
     use super::*;
@@ -168,6 +221,65 @@ mod fragment_pool_tests {
     }
 }
 
+#[cfg(test)]
+mod buffer_fragment_pool_tests {
+    use super::*;
+    use crate::mempool::CountingMemPool;
+
+    #[test]
+    fn reuse_and_reclaim() {
+        let alloc = CountingMemPool::new(8);
+
+        {
+            let mut pool = BufferFragmentPool::new(3, 1 << 8);
+            for _ in 0..4 {
+                pool.lossy_push(alloc.allocate());
+            }
+            // The one which didn't fit went straight back
+            assert_eq!(pool.len(), 3);
+            assert_eq!(alloc.allocations(), 4);
+            assert_eq!(alloc.frees(), 1);
+
+            let reused = pool.pop_or_allocate(&alloc);
+            assert_eq!(alloc.allocations(), 4);
+            assert_eq!(pool.len(), 2);
+            pool.lossy_push(reused);
+
+            while !pool.is_empty() {
+                pool.maybe_pop();
+            }
+            let fresh = pool.pop_or_allocate(&alloc);
+            assert_eq!(alloc.allocations(), 5);
+            pool.lossy_push(fresh);
+            pool.lossy_push(alloc.allocate());
+        }
+
+        assert_eq!(alloc.outstanding(), 0);
+    }
+
+    #[test]
+    fn partial_buffers() {
+        use crate::cursor::DirectBuf;
+        use bytes::Buf;
+
+        let alloc = CountingMemPool::new(8);
+        let mut pool = BufferFragmentPool::new(3, 1 << 8);
+
+        // A buffer which has been cut short isn't kept
+        let mut short = alloc.allocate();
+        DirectBuf::truncate(&mut short, 16);
+        pool.lossy_push(short);
+        assert!(pool.is_empty());
+        assert_eq!(alloc.outstanding(), 0);
+
+        // Whole ones come back out at their full size
+        pool.lossy_push(alloc.allocate());
+        let reused = pool.pop_or_allocate(&alloc);
+        assert_eq!(reused.remaining(), 1 << 8);
+        assert_eq!(alloc.allocations(), 2);
+    }
+}
+
 //// Generic container for a single logical 'packet'.
 //pub struct Packet<T> {
 
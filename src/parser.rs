@@ -16,13 +16,40 @@
  */
 
 use super::cursor;
+use ::bytes::buf::{Buf, BufExt, BufMut};
 use nom::*;
 
 #[derive(Debug, PartialEq)]
-pub enum VarintParseFail {
+pub enum ParseFail {
     VarintExceededShift(usize),
+    /// The varint ran off the end of data which is known to be complete, such as a frame body
+    VarintTruncated,
+    /// A boolean field held something other than 0 or 1
+    InvalidBoolean(u8),
+    /// A length prefix was negative or longer than the field allows
+    InvalidLength(i32),
+    /// The varint was padded out to more bytes than its value needs. usize is how many it took.
+    VarintOverlong(usize),
 }
 
+impl std::fmt::Display for ParseFail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseFail::VarintExceededShift(shift) => {
+                write!(f, "varint is longer than {} bits", shift)
+            }
+            ParseFail::VarintTruncated => f.write_str("varint runs past the end of the data"),
+            ParseFail::InvalidBoolean(b) => write!(f, "{:#04x} is not a boolean", b),
+            ParseFail::InvalidLength(len) => write!(f, "length {} is out of range", len),
+            ParseFail::VarintOverlong(len) => {
+                write!(f, "varint is padded out to {} bytes", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseFail {}
+
 macro_rules! varint_decode {
     ($input:expr, $max_shift:expr, $typ:ty) => {{
         let mut i = 0;
@@ -39,26 +66,228 @@ macro_rules! varint_decode {
 
             i += 7;
             if i > $max_shift {
-                return Err(nom::Err::Error(VarintParseFail::VarintExceededShift(
-                    $max_shift,
-                )));
+                return Err(nom::Err::Error(ParseFail::VarintExceededShift($max_shift)));
             }
         }
     }};
 }
 
-pub fn varint<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i32, VarintParseFail> {
+pub fn varint<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i32, ParseFail> {
     varint_decode!(b, 32, i32)
 }
 
-pub fn varlong<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i64, VarintParseFail> {
+/// The same as varint, but decodes straight out of the current page when the whole varint is in it,
+/// which is nearly always. Only varints which span pages or are malformed go byte by byte.
+#[inline]
+pub fn varint_fast<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i32, ParseFail> {
+    let (result, len) = {
+        let page = b.bytes();
+        match page.first() {
+            Some(&first) if first & 0x80 == 0 => (first as i32, 1),
+            _ => match page.iter().take(5).position(|x| x & 0x80 == 0) {
+                Some(end) => {
+                    let result = page[..=end]
+                        .iter()
+                        .enumerate()
+                        .fold(0, |r, (i, x)| r | ((x & 0x7f) as i32) << (i * 7));
+                    (result, end + 1)
+                }
+                None => return varint(b),
+            },
+        }
+    };
+    b.advance(len);
+    Ok((b, result))
+}
+
+pub fn varlong<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i64, ParseFail> {
     varint_decode!(b, 64, i64);
 }
 
+/// The same as varint, but also returns how many bytes the varint took up
+pub fn varint_counted<T: cursor::SliceCursor>(b: T) -> IResult<T, (i32, usize), ParseFail> {
+    let before = b.remaining();
+    let (b, v) = varint(b)?;
+    let len = before - b.remaining();
+    Ok((b, (v, len)))
+}
+
+/// The same as varlong, but also returns how many bytes the varlong took up
+pub fn varlong_counted<T: cursor::SliceCursor>(b: T) -> IResult<T, (i64, usize), ParseFail> {
+    let before = b.remaining();
+    let (b, v) = varlong(b)?;
+    let len = before - b.remaining();
+    Ok((b, (v, len)))
+}
+
+// A varint is padded if its last byte carried nothing but zeroes, which the shortest encoding of
+// any value other than 0 never does
+fn check_padding(v: u64, len: usize) -> Result<(), nom::Err<ParseFail>> {
+    if len > 1 && v >> (7 * (len - 1)) == 0 {
+        Err(nom::Err::Error(ParseFail::VarintOverlong(len)))
+    } else {
+        Ok(())
+    }
+}
+
+/// The same as varint, but rejects varints encoded with more bytes than their value needs
+pub fn varint_strict<T: cursor::SliceCursor>(b: T) -> IResult<T, i32, ParseFail> {
+    let (b, (v, len)) = varint_counted(b)?;
+    check_padding(v as u32 as u64, len)?;
+    Ok((b, v))
+}
+
+/// The same as varlong, but rejects varlongs encoded with more bytes than their value needs
+pub fn varlong_strict<T: cursor::SliceCursor>(b: T) -> IResult<T, i64, ParseFail> {
+    let (b, (v, len)) = varlong_counted(b)?;
+    check_padding(v as u64, len)?;
+    Ok((b, v))
+}
+
+/// Decodes a varint which may be encoded with at most max_bytes bytes. This rejects fields padded
+/// out with redundant continuation bytes without having to read the whole varint first. The cap is
+/// never looser than what varint allows, and at least one byte is always read.
+pub fn varint_max<T: cursor::SliceCursor>(
+    mut b: T,
+    max_bytes: usize,
+) -> IResult<T, i32, ParseFail> {
+    let max_shift = std::cmp::min(max_bytes.saturating_sub(1).saturating_mul(7), 32);
+    varint_decode!(b, max_shift, i32)
+}
+
+pub fn u8<T: cursor::SliceCursor>(mut b: T) -> IResult<T, u8, ParseFail> {
+    if !b.has_atleast(1) {
+        return Err(nom::Err::Incomplete(Needed::Size(1)));
+    }
+    let v = b.get_u8();
+    Ok((b, v))
+}
+
+pub fn i8<T: cursor::SliceCursor>(mut b: T) -> IResult<T, i8, ParseFail> {
+    if !b.has_atleast(1) {
+        return Err(nom::Err::Incomplete(Needed::Size(1)));
+    }
+    let v = b.get_i8();
+    Ok((b, v))
+}
+
+macro_rules! fixed_le {
+    ($name: ident, $typ: ty, $get: ident) => {
+        /// A little endian field. The protocol is big endian almost everywhere, so these are only
+        /// for the odd field which isn't.
+        pub fn $name<T: cursor::SliceCursor>(mut b: T) -> IResult<T, $typ, ParseFail> {
+            let len = std::mem::size_of::<$typ>();
+            if !b.has_atleast(len) {
+                return Err(nom::Err::Incomplete(Needed::Size(len)));
+            }
+            let v = b.$get();
+            Ok((b, v))
+        }
+    };
+}
+
+fixed_le!(u16_le, u16, get_u16_le);
+fixed_le!(u32_le, u32, get_u32_le);
+fixed_le!(i64_le, i64, get_i64_le);
+
+/// A single byte which must be either 0 (false) or 1 (true)
+pub fn boolean<T: cursor::SliceCursor>(b: T) -> IResult<T, bool, ParseFail> {
+    let (b, v) = u8(b)?;
+    match v {
+        0 => Ok((b, false)),
+        1 => Ok((b, true)),
+        _ => Err(nom::Err::Error(ParseFail::InvalidBoolean(v))),
+    }
+}
+
+/// A varint length followed by that many raw bytes, which are copied out into a Bytes. Lengths
+/// over max_len are rejected before waiting on the rest of the data to arrive.
+pub fn byte_array<T: cursor::SliceCursor>(
+    b: T,
+    max_len: usize,
+) -> IResult<T, ::bytes::Bytes, ParseFail> {
+    let (mut b, len) = varint(b)?;
+    if len < 0 || len as usize > max_len {
+        return Err(nom::Err::Error(ParseFail::InvalidLength(len)));
+    }
+    let len = len as usize;
+    if !b.has_atleast(len) {
+        return Err(nom::Err::Incomplete(Needed::Size(len)));
+    }
+    let v = (&mut b).take(len).to_bytes();
+    Ok((b, v))
+}
+
+/// Decodes a varint count followed by that many elements, each decoded by parse_elem. Counts which
+/// are negative or above max are rejected before anything is allocated for them.
+pub fn array<T, E, F>(b: T, max: usize, mut parse_elem: F) -> IResult<T, Vec<E>, ParseFail>
+where
+    T: cursor::SliceCursor,
+    F: FnMut(T) -> IResult<T, E, ParseFail>,
+{
+    let (mut b, count) = varint(b)?;
+    if count < 0 || count as usize > max {
+        return Err(nom::Err::Error(ParseFail::InvalidLength(count)));
+    }
+
+    let mut elems = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (rest, elem) = parse_elem(b)?;
+        b = rest;
+        elems.push(elem);
+    }
+    Ok((b, elems))
+}
+
+/// How many bytes v takes up once encoded as a varint
+pub fn varint_len(v: i32) -> usize {
+    let v = v as u32;
+    match v.leading_zeros() {
+        0..=3 => 5,
+        4..=10 => 4,
+        11..=17 => 3,
+        18..=24 => 2,
+        _ => 1,
+    }
+}
+
+/// Encodes v as a varint into out, returning how many bytes were written. If out doesn't have
+/// room for all of it (a varint is at most 5 bytes long) nothing is written and 0 is returned.
+pub fn encode_varint<B: BufMut>(v: i32, out: &mut B) -> usize {
+    let len = varint_len(v);
+    if out.remaining_mut() < len {
+        return 0;
+    }
+
+    let mut v = v as u32;
+    for _ in 1..len {
+        out.put_u8((v & 0x7f) as u8 | 0x80);
+        v >>= 7;
+    }
+    out.put_u8(v as u8);
+    len
+}
+
+/// Encodes s as a string - its length in bytes as a varint followed by its UTF-8 - into out,
+/// returning how many bytes were written. Like encode_varint, if out doesn't have room for all of
+/// it nothing is written and 0 is returned.
+pub fn encode_mc_string<B: BufMut>(s: &str, out: &mut B) -> usize {
+    let len = varint_len(s.len() as i32) + s.len();
+    if out.remaining_mut() < len {
+        return 0;
+    }
+
+    encode_varint(s.len() as i32, out);
+    out.put_slice(s.as_bytes());
+    len
+}
+
 #[cfg(test)]
 mod test {
+    extern crate test;
+
     use super::*;
-    use ::bytes::BytesMut;
+    use ::bytes::{Buf, BytesMut};
     use std::iter::FromIterator;
 
     macro_rules! to_buf {
@@ -73,6 +302,147 @@ mod test {
         };
     }
 
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            ParseFail::VarintExceededShift(32).to_string(),
+            "varint is longer than 32 bits"
+        );
+        assert_eq!(
+            ParseFail::VarintTruncated.to_string(),
+            "varint runs past the end of the data"
+        );
+        assert_eq!(
+            ParseFail::InvalidBoolean(2).to_string(),
+            "0x02 is not a boolean"
+        );
+        assert_eq!(
+            ParseFail::InvalidLength(-1).to_string(),
+            "length -1 is out of range"
+        );
+        assert_eq!(
+            ParseFail::VarintOverlong(3).to_string(),
+            "varint is padded out to 3 bytes"
+        );
+    }
+
+    #[test]
+    fn strict_varints() {
+        assert_eq!(varint_strict(to_buf!([0x00])).unwrap(), (to_buf!([]), 0));
+        assert_eq!(
+            varint_strict(to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f])).unwrap(),
+            (to_buf!([]), -1)
+        );
+        assert_eq!(
+            varint_strict(to_buf!([0x80, 0x00])).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintOverlong(2))
+        );
+        assert_eq!(
+            varint_strict(to_buf!([0x81, 0x80, 0x00])).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintOverlong(3))
+        );
+        assert_eq!(
+            varlong_strict(to_buf!([0x80, 0x01])).unwrap(),
+            (to_buf!([]), 128)
+        );
+        assert_eq!(
+            varlong_strict(to_buf!([0xff, 0x80, 0x00])).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintOverlong(3))
+        );
+    }
+
+    #[test]
+    fn little_endian() {
+        assert_eq!(
+            u16_le(to_buf!([0x34, 0x12, 0xff])).unwrap(),
+            (to_buf!([0xff]), 0x1234)
+        );
+        assert_eq!(
+            u32_le(to_buf!([0x78, 0x56, 0x34, 0x12])).unwrap(),
+            (to_buf!([]), 0x12345678)
+        );
+        assert_eq!(
+            i64_le(to_buf!([0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])).unwrap(),
+            (to_buf!([]), -2)
+        );
+        assert_eq!(
+            u32_le(to_buf!([0x78, 0x56, 0x34])).unwrap_err(),
+            nom::Err::Incomplete(Needed::Size(4))
+        );
+
+        // Spanning two pages
+        let mut vd = std::collections::VecDeque::new();
+        vd.push_back(to_buf!([0x01, 0x78]));
+        vd.push_back(to_buf!([0x56, 0x34, 0x12, 0x09]));
+        let mb = cursor::Multibytes::new(vd);
+        let mut view = mb.view();
+        view.advance(1);
+        let (view, v) = u32_le(view).unwrap();
+        assert_eq!(v, 0x12345678);
+        assert_eq!(view.remaining(), 1);
+    }
+
+    #[test]
+    fn byte_array_test() {
+        assert_eq!(
+            byte_array(to_buf!([0x02, 0xaa, 0xbb, 0xcc]), 16).unwrap(),
+            (to_buf!([0xcc]), to_buf!([0xaa, 0xbb]))
+        );
+        assert_eq!(
+            byte_array(to_buf!([0x00]), 16).unwrap(),
+            (to_buf!([]), to_buf!([]))
+        );
+        assert_eq!(
+            byte_array(to_buf!([0x03, 0xaa]), 16).unwrap_err(),
+            nom::Err::Incomplete(Needed::Size(3))
+        );
+        assert_eq!(
+            byte_array(to_buf!([0x11]), 16).unwrap_err(),
+            nom::Err::Error(ParseFail::InvalidLength(17))
+        );
+        assert_eq!(
+            byte_array(to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f]), 16).unwrap_err(),
+            nom::Err::Error(ParseFail::InvalidLength(-1))
+        );
+
+        // The length and the payload both span pages
+        let mut vd = std::collections::VecDeque::new();
+        vd.push_back(to_buf!([0x81]));
+        vd.push_back(to_buf!([0x01, 0x00, 0x01]));
+        vd.push_back(::bytes::Bytes::from(vec![0x02; 126]));
+        vd.push_back(to_buf!([0x03, 0x04]));
+        let mb = cursor::Multibytes::new(vd);
+        let (view, v) = byte_array(mb.view(), 1024).unwrap();
+        assert_eq!(view.remaining(), 1);
+        assert_eq!(v.len(), 129);
+        assert_eq!(&v[..3], [0x00, 0x01, 0x02]);
+        assert_eq!(v[128], 0x03);
+    }
+
+    #[test]
+    fn fixed_bytes() {
+        assert_eq!(boolean(to_buf!([0x00])).unwrap(), (to_buf!([]), false));
+        assert_eq!(
+            boolean(to_buf!([0x01, 0x05])).unwrap(),
+            (to_buf!([0x05]), true)
+        );
+        assert_eq!(
+            boolean(to_buf!([0x02])).unwrap_err(),
+            nom::Err::Error(ParseFail::InvalidBoolean(2))
+        );
+        assert_eq!(
+            boolean(to_buf!([])).unwrap_err(),
+            nom::Err::Incomplete(Needed::Size(1))
+        );
+
+        assert_eq!(u8(to_buf!([0xff])).unwrap(), (to_buf!([]), 255));
+        assert_eq!(i8(to_buf!([0xff])).unwrap(), (to_buf!([]), -1));
+        assert_eq!(
+            i8(to_buf!([])).unwrap_err(),
+            nom::Err::Incomplete(Needed::Size(1))
+        );
+    }
+
     #[test]
     fn varint_test() {
         varint_test!(varint, 0, to_buf!([0x00]));
@@ -86,6 +456,150 @@ mod test {
         varint_test!(varint, -2147483648, to_buf!([0x80, 0x80, 0x80, 0x80, 0x08]));
     }
 
+    #[test]
+    fn varint_fast_test() {
+        varint_test!(varint_fast, 0, to_buf!([0x00]));
+        varint_test!(varint_fast, 127, to_buf!([0x7f]));
+        varint_test!(varint_fast, 128, to_buf!([0x80, 0x01]));
+        varint_test!(varint_fast, 255, to_buf!([0xff, 0x01]));
+        varint_test!(
+            varint_fast,
+            2147483647,
+            to_buf!([0xff, 0xff, 0xff, 0xff, 0x07])
+        );
+        varint_test!(varint_fast, -1, to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f]));
+        assert_eq!(
+            varint_fast(to_buf!([0x80, 0x01, 0x02])).unwrap(),
+            (to_buf!([0x02]), 128)
+        );
+        assert_eq!(
+            varint_fast(to_buf!([0x80, 0x80, 0x80, 0x80, 0x80])).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintExceededShift(32))
+        );
+        assert_eq!(
+            varint_fast(to_buf!([0x80, 0x80])).unwrap_err(),
+            nom::Err::Incomplete(Needed::Unknown)
+        );
+
+        // Spanning pages takes the slow path, but has to come out the same
+        let mut vd = std::collections::VecDeque::new();
+        vd.push_back(to_buf!([0xff, 0xff]));
+        vd.push_back(to_buf!([0xff, 0x01, 0x05]));
+        let mb = cursor::Multibytes::new(vd);
+        let (view, v) = varint_fast(mb.view()).unwrap();
+        assert_eq!(v, 4194303);
+        assert_eq!(view.remaining(), 1);
+    }
+
+    #[test]
+    fn array_test() {
+        assert_eq!(
+            array(to_buf!([0x3, 0x1, 0xff, 0x01, 0x0, 0x7]), 4, varint).unwrap(),
+            (to_buf!([0x7]), vec![1, 255, 0])
+        );
+        assert_eq!(
+            array(to_buf!([0x0, 0x7]), 0, varint).unwrap(),
+            (to_buf!([0x7]), vec![])
+        );
+        // The third element hasn't arrived yet
+        assert_eq!(
+            array(to_buf!([0x3, 0x1, 0x2]), 4, varint).unwrap_err(),
+            nom::Err::Incomplete(Needed::Unknown)
+        );
+        assert_eq!(
+            array(to_buf!([0x5, 0x1, 0x2]), 4, varint).unwrap_err(),
+            nom::Err::Error(ParseFail::InvalidLength(5))
+        );
+        assert_eq!(
+            array(to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f]), 4, u8).unwrap_err(),
+            nom::Err::Error(ParseFail::InvalidLength(-1))
+        );
+    }
+
+    #[test]
+    fn encode_varint_test() {
+        for v in [0, 1, 127, 128, 255, 25565, 2147483647, -1, -2147483648].iter() {
+            let mut out = [0; 5];
+            let n = encode_varint(*v, &mut &mut out[..]);
+            assert_eq!(n, varint_len(*v));
+            assert_eq!(varint(to_buf!(out[..n])).unwrap(), (to_buf!([]), *v));
+        }
+
+        // Too small for the whole varint, so none of it is written
+        let mut out = [0; 2];
+        assert_eq!(encode_varint(25565, &mut &mut out[..]), 0);
+        assert_eq!(out, [0, 0]);
+    }
+
+    global_mempool_tlmp!(encode_into_part_pool, 4);
+    #[test]
+    fn encode_into_part() {
+        use crate::cursor::DirectBuf;
+        use crate::mempool::{self, BlockAllocator};
+
+        let pool = mempool::GlobalMemPool::new(
+            &encode_into_part_pool,
+            mempool::GlobalMemPoolSettings {
+                buf_size: 5,
+                page_entries: 4,
+                concurrent_allocation_limit: 1,
+                ..Default::default()
+            },
+        );
+        let mut part = pool.allocate();
+
+        let written = {
+            let mut out = part.as_mut();
+            let mut n = encode_varint(300, &mut out);
+            n += encode_mc_string("blockrouter", &mut out);
+            // The part holds 28 bytes, so only 14 are left - not enough for this one
+            assert_eq!(encode_mc_string("too long to fit!", &mut out), 0);
+            n
+        };
+        assert_eq!(written, 14);
+        part.truncate(written);
+
+        let b = cursor::Multibytes::from_buf(part);
+        let (b, v) = varint(b).unwrap();
+        assert_eq!(v, 300);
+        let (b, s) = byte_array(b, 32).unwrap();
+        assert_eq!(&s[..], b"blockrouter");
+        assert_eq!(b.remaining(), 0);
+    }
+
+    #[test]
+    fn varint_counted_test() {
+        assert_eq!(
+            varint_counted(to_buf!([0x00, 0x01])).unwrap(),
+            (to_buf!([0x01]), (0, 1))
+        );
+        assert_eq!(
+            varint_counted(to_buf!([0xff, 0x01])).unwrap(),
+            (to_buf!([]), (255, 2))
+        );
+        assert_eq!(
+            varint_counted(to_buf!([0xff, 0xff, 0xff, 0xff, 0x0f])).unwrap(),
+            (to_buf!([]), (-1, 5))
+        );
+        assert_eq!(
+            varlong_counted(to_buf!([0xff, 0xff, 0xff, 0xff, 0x07, 0x02])).unwrap(),
+            (to_buf!([0x02]), (2147483647, 5))
+        );
+        assert_eq!(
+            varint_counted(to_buf!([0x80])).unwrap_err(),
+            nom::Err::Incomplete(Needed::Unknown)
+        );
+
+        // Across pages
+        let mut vd = std::collections::VecDeque::new();
+        vd.push_back(to_buf!([0x80, 0x80]));
+        vd.push_back(to_buf!([0x01, 0x09]));
+        let mb = cursor::Multibytes::new(vd);
+        let (view, (v, len)) = varint_counted(mb.view()).unwrap();
+        assert_eq!((v, len), (16384, 3));
+        assert_eq!(view.remaining(), 1);
+    }
+
     #[test]
     fn varlong_test() {
         varint_test!(varlong, 0, to_buf!([0x00]));
@@ -121,7 +635,7 @@ mod test {
     fn varint_blowout() {
         assert_eq!(
             varint(to_buf!([0x80, 0x80, 0x80, 0x80, 0x80])).unwrap_err(),
-            nom::Err::Error(VarintParseFail::VarintExceededShift(32))
+            nom::Err::Error(ParseFail::VarintExceededShift(32))
         );
     }
 
@@ -132,7 +646,32 @@ mod test {
                 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80
             ]))
             .unwrap_err(),
-            nom::Err::Error(VarintParseFail::VarintExceededShift(64))
+            nom::Err::Error(ParseFail::VarintExceededShift(64))
+        );
+    }
+
+    #[test]
+    fn varint_max_test() {
+        assert_eq!(
+            varint_max(to_buf!([0x80, 0x01]), 2).unwrap(),
+            (to_buf!([]), 128)
+        );
+        assert_eq!(
+            varint_max(to_buf!([0x80, 0x81, 0x00]), 2).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintExceededShift(7))
+        );
+        assert_eq!(
+            varint_max(to_buf!([0x80, 0x81, 0x00]), 3).unwrap(),
+            (to_buf!([]), 128)
+        );
+        // Caps larger than a varint can be still follow varint's rules
+        assert_eq!(
+            varint_max(to_buf!([0x80, 0x80, 0x80, 0x80, 0x80]), 10).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintExceededShift(32))
+        );
+        assert_eq!(
+            varint_max(to_buf!([0x80, 0x80, 0x80, 0x80, 0x80]), usize::MAX).unwrap_err(),
+            nom::Err::Error(ParseFail::VarintExceededShift(32))
         );
     }
 
@@ -156,4 +695,39 @@ mod test {
             (to_buf!([0x02]), 1)
         );
     }
+
+    // A million single byte varints, spread over pages like the framer would see them
+    fn one_byte_varints() -> cursor::Multibytes<::bytes::Bytes> {
+        let mut vd = std::collections::VecDeque::new();
+        for _ in 0..16 {
+            vd.push_back(::bytes::Bytes::from(vec![0x2a; 1 << 16]));
+        }
+        cursor::Multibytes::new(vd)
+    }
+
+    #[bench]
+    fn bench_varint(b: &mut test::Bencher) {
+        let data = one_byte_varints();
+        b.iter(|| {
+            let mut view = data.view();
+            while view.has_remaining() {
+                let (rest, v) = varint(view).unwrap();
+                test::black_box(v);
+                view = rest;
+            }
+        })
+    }
+
+    #[bench]
+    fn bench_varint_fast(b: &mut test::Bencher) {
+        let data = one_byte_varints();
+        b.iter(|| {
+            let mut view = data.view();
+            while view.has_remaining() {
+                let (rest, v) = varint_fast(view).unwrap();
+                test::black_box(v);
+                view = rest;
+            }
+        })
+    }
 }
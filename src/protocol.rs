@@ -0,0 +1,32 @@
+/*
+ *  Copyright (C) 2020  Joe Hirschfeld <j@ibj.io>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// The state a connection is in, which decides what each packet id means. Every connection starts
+/// out Handshaking, and it is up to whoever understands the packets to move it along.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProtocolState {
+    Handshaking,
+    Status,
+    Login,
+    Play,
+}
+
+impl Default for ProtocolState {
+    fn default() -> ProtocolState {
+        ProtocolState::Handshaking
+    }
+}
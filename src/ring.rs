@@ -17,8 +17,15 @@
 
 use std::alloc;
 use std::cell::Cell;
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+#[cfg(debug_assertions)]
+use std::collections::HashMap;
+use std::io::IoSlice;
 use std::mem::{align_of, size_of, ManuallyDrop, MaybeUninit};
 
+use super::cursor::DirectBuf;
+
 #[derive(Copy, Clone)]
 struct FrameHeader {
     // The index of the next frame header
@@ -38,6 +45,14 @@ union RingElement<T> {
     element: ManuallyDrop<T>,
 }
 
+// Which side of a RingElement was last written. Debug builds keep track of this for every slot so
+// that reading the wrong side - a frame index bug - panics rather than quietly reading garbage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tag {
+    Header,
+    Element,
+}
+
 pub struct FramedRing<T> {
     // This is generally a datastructure which does a lot of 'unsafe' stuff to be efficient
     ring: Cell<*mut MaybeUninit<RingElement<T>>>,
@@ -47,6 +62,9 @@ pub struct FramedRing<T> {
     head: Cell<usize>,
     // ring_size = 2 pow ring_size_2
     ring_size_2: Cell<u8>,
+    // What was last written to each slot, by its masked index
+    #[cfg(debug_assertions)]
+    tags: RefCell<HashMap<usize, Tag>>,
 }
 
 impl<T> FramedRing<T> {
@@ -56,6 +74,8 @@ impl<T> FramedRing<T> {
             base: Cell::new(0),
             head: Cell::new(0),
             ring_size_2: Cell::new(0),
+            #[cfg(debug_assertions)]
+            tags: RefCell::new(HashMap::new()),
         }
     }
 
@@ -65,12 +85,15 @@ impl<T> FramedRing<T> {
             panic!("attempting to start a new root frame with already-modifiable frames are using this ring");
         }
 
-        self.append_to_ring(RingElement {
-            header: FrameHeader {
-                next: start + 1,
-                is_live: true,
+        self.append_to_ring(
+            RingElement {
+                header: FrameHeader {
+                    next: start + 1,
+                    is_live: true,
+                },
             },
-        });
+            Tag::Header,
+        );
 
         RingFrameMut {
             f: RingFrame {
@@ -81,7 +104,7 @@ impl<T> FramedRing<T> {
         }
     }
 
-    fn append_to_ring(&self, re: RingElement<T>) {
+    fn append_to_ring(&self, re: RingElement<T>, _tag: Tag) {
         let len = 1 << self.ring_size_2.get();
         // len is always a power of 2 (see above definition) - this is a cheap way of performing a
         // % operation.
@@ -93,9 +116,10 @@ impl<T> FramedRing<T> {
         self.head.set(old_head + 1);
 
         let base_i = self.base.get() & mask;
-        let old_head_i = old_head & mask;
 
-        if base_i == old_head_i {
+        // An empty ring has base == head as well, but only a full (or not yet allocated) ring has
+        // to grow
+        if self.ring.get().is_null() || old_head - self.base.get() == len {
             unsafe {
                 // We have run out of space, double the size and copy stuff over in a way that
                 // isn't stupid
@@ -142,6 +166,16 @@ impl<T> FramedRing<T> {
                     old_head - pivot_point,
                 );
 
+                // The tags of every live slot move along with them
+                #[cfg(debug_assertions)]
+                {
+                    let mut tags = self.tags.borrow_mut();
+                    let moved = (self.base.get()..old_head)
+                        .filter_map(|i| tags.get(&(i & (len - 1))).map(|&tag| (i & mask, tag)))
+                        .collect();
+                    *tags = moved;
+                }
+
                 let old_buffer_layout = alloc::Layout::from_size_align_unchecked(
                     size_of::<RingElement<T>>() << (self.ring_size_2.get() - 1),
                     align_of::<RingElement<T>>(),
@@ -161,6 +195,80 @@ impl<T> FramedRing<T> {
                 .add(old_head & mask)
                 .write(MaybeUninit::new(re));
         }
+
+        #[cfg(debug_assertions)]
+        self.tags.borrow_mut().insert(old_head & mask, _tag);
+    }
+
+    /// The number of elements (frame headers included) the ring can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        if self.ring.get().is_null() {
+            0
+        } else {
+            1 << self.ring_size_2.get()
+        }
+    }
+
+    /// Grows the ring in one go so that another `additional` elements (frame headers included) can
+    /// be appended without it having to grow again
+    pub fn reserve(&mut self, additional: usize) {
+        self.reserve_span(additional)
+    }
+
+    // Makes sure that another `additional` elements can be appended without the ring having to
+    // grow, growing it all in one go if it can't.
+    fn reserve_span(&self, additional: usize) {
+        let needed = self.head.get() - self.base.get() + additional;
+        if needed <= self.capacity() {
+            return;
+        }
+
+        let mut new_size_2 = self.ring_size_2.get();
+        while (1 << new_size_2) < needed {
+            new_size_2 += 1;
+        }
+
+        unsafe { self.resize(new_size_2) }
+    }
+
+    unsafe fn resize(&self, new_size_2: u8) {
+        let old_mask = self.mask();
+        let new_mask = (1 << new_size_2) - 1;
+
+        let new_buffer_layout = alloc::Layout::from_size_align_unchecked(
+            size_of::<RingElement<T>>() << new_size_2,
+            align_of::<RingElement<T>>(),
+        );
+        let new_buffer: *mut MaybeUninit<RingElement<T>> =
+            std::mem::transmute(alloc::alloc(new_buffer_layout));
+
+        // Copy everything from base to head over in runs, each going up until either the old or
+        // the new buffer wraps around
+        let head = self.head.get();
+        let mut i = self.base.get();
+        while i < head {
+            let n = std::cmp::min(
+                head - i,
+                std::cmp::min(old_mask + 1 - (i & old_mask), new_mask + 1 - (i & new_mask)),
+            );
+            std::ptr::copy_nonoverlapping(
+                self.ring.get().add(i & old_mask),
+                new_buffer.add(i & new_mask),
+                n,
+            );
+            i += n;
+        }
+
+        if !self.ring.get().is_null() {
+            let old_buffer_layout = alloc::Layout::from_size_align_unchecked(
+                size_of::<RingElement<T>>() << self.ring_size_2.get(),
+                align_of::<RingElement<T>>(),
+            );
+            std::alloc::dealloc(std::mem::transmute(self.ring.get()), old_buffer_layout);
+        }
+
+        self.ring.set(new_buffer);
+        self.ring_size_2.set(new_size_2);
     }
 
     pub fn try_promote<'ring>(
@@ -168,7 +276,7 @@ impl<T> FramedRing<T> {
         frame: RingFrame<'ring, T>,
     ) -> Option<RingFrameMut<'ring, T>> {
         unsafe {
-            let header = self.get(frame.start).header;
+            let header = *self.header(frame.start);
             if header.next != self.head.get() {
                 None
             } else {
@@ -210,6 +318,30 @@ impl<T> FramedRing<T> {
     unsafe fn get_masked_mut<'a>(&'a self, i: usize) -> &'a mut RingElement<T> {
         std::mem::transmute(&mut *self.ring.get().add(i))
     }
+
+    #[cfg(debug_assertions)]
+    fn check_tag(&self, i: usize, want: Tag) {
+        match self.tags.borrow().get(&(i & self.mask())) {
+            Some(&tag) if tag == want => {}
+            tag => panic!("ring slot {} read as {:?}, but holds {:?}", i, want, tag),
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn check_tag(&self, _i: usize, _want: Tag) {}
+
+    // The frame header at unmasked index i
+    unsafe fn header(&self, i: usize) -> &FrameHeader {
+        self.check_tag(i, Tag::Header);
+        &self.get(i).header
+    }
+
+    // The frame element at unmasked index i
+    unsafe fn element(&self, i: usize) -> &ManuallyDrop<T> {
+        self.check_tag(i, Tag::Element);
+        &self.get(i).element
+    }
 }
 
 impl<T> Drop for FramedRing<T> {
@@ -245,12 +377,12 @@ impl<'ring, T> Drop for RingFrame<'ring, T> {
         // in the ring (not that it matters to us, but interesting to know).
 
         unsafe {
-            // This mask is only valid over while the ring doesn't change size.
-            let mask = self.ring.mask();
-            let mut header = &mut self.ring.get_masked_mut(self.start & mask).header;
+            self.ring.check_tag(self.start, Tag::Header);
+            let header = &mut self.ring.get_mut(self.start).header;
             if std::mem::needs_drop::<T>() {
                 for i in self.live_at..header.next {
-                    ManuallyDrop::drop(&mut self.ring.get_masked_mut(i & mask).element);
+                    self.ring.check_tag(i, Tag::Element);
+                    ManuallyDrop::drop(&mut self.ring.get_mut(i).element);
                 }
             }
 
@@ -279,7 +411,8 @@ impl<'ring, T> Drop for RingFrame<'ring, T> {
                         return;
                     }
 
-                    working_header = &mut self.ring.get_masked_mut(header_idx & mask).header;
+                    self.ring.check_tag(header_idx, Tag::Header);
+                    working_header = &mut self.ring.get_mut(header_idx).header;
                     if working_header.is_live {
                         // Exit - this frame is still being used, and is now the new base
                         self.ring.base.set(header_idx);
@@ -309,7 +442,7 @@ impl<'a, T> Iterator for RingFrameIter<'a, T> {
             None
         } else {
             unsafe {
-                let item = &self.ring.get(self.i).element;
+                let item = self.ring.element(self.i);
                 self.i += 1;
                 Some(item)
             }
@@ -330,9 +463,10 @@ impl<'a, T> Iterator for RingFrameIntoIter<'a, T> {
             None
         } else {
             unsafe {
-                // This is basically what happens in self.f.ring.get(), but without doing memory
-                // transmutation because we actually don't want to do it here.
-                let element = (&*self.f.ring.ring.get().add(self.f.live_at))
+                // This is basically what happens in self.f.ring.element(), but without doing
+                // memory transmutation because we actually don't want to do it here.
+                self.f.ring.check_tag(self.f.live_at, Tag::Element);
+                let element = (&*self.f.ring.ring.get().add(self.f.live_at & self.f.ring.mask()))
                     .read()
                     .element;
                 self.f.live_at += 1;
@@ -344,7 +478,7 @@ impl<'a, T> Iterator for RingFrameIntoIter<'a, T> {
 
 impl<'ring, T> RingFrame<'ring, T> {
     fn header<'a>(&'a self) -> &'a FrameHeader {
-        unsafe { &self.ring.get(self.start).header }
+        unsafe { self.ring.header(self.start) }
     }
 
     pub fn len(&self) -> usize {
@@ -356,12 +490,12 @@ impl<'ring, T> RingFrame<'ring, T> {
         if i >= self.header().next {
             None
         } else {
-            Some(unsafe { &self.ring.get(i).element })
+            Some(unsafe { self.ring.element(i) })
         }
     }
 
     pub unsafe fn get_unchecked(&self, index: usize) -> &T {
-        &self.ring.get(self.start + index + 1).element
+        self.ring.element(self.start + index + 1)
     }
 
     pub fn iter<'a>(&'a self) -> RingFrameIter<'a, T> {
@@ -373,13 +507,29 @@ impl<'ring, T> RingFrame<'ring, T> {
     }
 }
 
+impl<'ring, T: DirectBuf> RingFrame<'ring, T> {
+    /// Fills dst with a slice of each non-empty buffer in this frame, in order, so that the frame
+    /// can be handed straight to a vectored write. Returns the number of slices filled.
+    pub fn bytes_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut n = 0;
+        for b in self.iter().filter(|b| b.remaining() > 0) {
+            if n >= dst.len() {
+                break;
+            }
+            dst[n] = IoSlice::new(b.as_ref());
+            n += 1;
+        }
+        n
+    }
+}
+
 impl<'ring, T> IntoIterator for RingFrame<'ring, T> {
     type Item = T;
     type IntoIter = RingFrameIntoIter<'ring, T>;
 
     fn into_iter(self) -> Self::IntoIter {
         unsafe {
-            let header = self.ring.get(self.start).header;
+            let header = *self.ring.header(self.start);
             RingFrameIntoIter {
                 f: self,
                 end: header.next,
@@ -392,12 +542,15 @@ impl<'ring, T> RingFrameMut<'ring, T> {
     pub fn next(self) -> (RingFrame<'ring, T>, RingFrameMut<'ring, T>) {
         // We need to produce a new frame header for the new frame
         let head = self.f.ring.head.get();
-        self.f.ring.append_to_ring(RingElement {
-            header: FrameHeader {
-                next: head + 1,
-                is_live: true,
+        self.f.ring.append_to_ring(
+            RingElement {
+                header: FrameHeader {
+                    next: head + 1,
+                    is_live: true,
+                },
             },
-        });
+            Tag::Header,
+        );
 
         let ring = self.f.ring;
 
@@ -415,14 +568,28 @@ impl<'ring, T> RingFrameMut<'ring, T> {
 
     pub fn append(&self, element: T) {
         unsafe {
-            self.f.ring.append_to_ring(RingElement {
-                element: ManuallyDrop::new(element),
-            });
+            self.f.ring.append_to_ring(
+                RingElement {
+                    element: ManuallyDrop::new(element),
+                },
+                Tag::Element,
+            );
 
+            self.f.ring.check_tag(self.f.start, Tag::Header);
             self.f.ring.get_mut(self.f.start).header.next += 1;
         }
     }
 
+    /// Appends every element of items in order. The ring is grown up front to fit as many elements
+    /// as the iterator says it will produce at least.
+    pub fn append_all<I: IntoIterator<Item = T>>(&self, items: I) {
+        let items = items.into_iter();
+        self.f.ring.reserve_span(items.size_hint().0);
+        for item in items {
+            self.append(item);
+        }
+    }
+
     pub fn inner<'a>(&'a self) -> &'a RingFrame<'ring, T> {
         &self.f
     }
@@ -484,6 +651,79 @@ mod tests {
         assert_eq!(frame3.inner().get(1024), None);
     }
 
+    #[test]
+    fn ring_append_all() {
+        let ring = FramedRing::<i32>::new();
+        let frame = ring.frame();
+        frame.append(-1);
+        frame.append_all(0..1000);
+
+        let (frame_ro, frame2) = frame.next();
+        frame2.append_all(vec![5, 6, 7]);
+        // Iterators which don't know how long they are work too
+        frame2.append_all((8..100).filter(|x| x % 2 == 0));
+
+        assert_eq!(frame_ro.len(), 1001);
+        assert_eq!(*frame_ro.get(0).unwrap(), -1);
+        for (i, v) in frame_ro.iter().skip(1).enumerate() {
+            assert_eq!(*v, i as i32);
+        }
+
+        let expected: Vec<i32> = vec![5, 6, 7]
+            .into_iter()
+            .chain((8..100).filter(|x| x % 2 == 0))
+            .collect();
+        assert_eq!(frame2.inner().len(), expected.len());
+        assert!(frame2.inner().iter().eq(expected.iter()));
+    }
+
+    #[test]
+    fn ring_reserve() {
+        let mut ring = FramedRing::<i32>::new();
+        assert_eq!(ring.capacity(), 0);
+        ring.reserve(1000);
+        let capacity = ring.capacity();
+        assert!(capacity >= 1000);
+
+        // One header and the rest elements
+        let frame = ring.frame();
+        for i in 0..999 {
+            frame.append(i);
+            assert_eq!(ring.capacity(), capacity);
+        }
+        assert_eq!(frame.inner().len(), 999);
+        drop(frame);
+
+        // Reusing the now empty ring doesn't need it to grow either
+        let frame = ring.frame();
+        frame.append_all(0..999);
+        assert_eq!(ring.capacity(), capacity);
+    }
+
+    #[test]
+    fn ring_bytes_vectored() {
+        let ring = FramedRing::<bytes::Bytes>::new();
+        let frame = ring.frame();
+        frame.append_all(vec![
+            bytes::Bytes::from_static(&[1, 2, 3]),
+            bytes::Bytes::new(),
+            bytes::Bytes::from_static(&[4]),
+            bytes::Bytes::from_static(&[5, 6]),
+        ]);
+
+        let mut dst = [IoSlice::new(&[]); 8];
+        let n = frame.inner().bytes_vectored(&mut dst);
+        assert_eq!(n, 3);
+        assert_eq!(&*dst[0], [1, 2, 3]);
+        assert_eq!(&*dst[1], [4]);
+        assert_eq!(&*dst[2], [5, 6]);
+
+        // Only as many slices as there is room for
+        let mut dst = [IoSlice::new(&[]); 2];
+        assert_eq!(frame.inner().bytes_vectored(&mut dst), 2);
+        assert_eq!(&*dst[1], [4]);
+    }
+
     #[test]
     #[should_panic]
     fn ring_double_frame_no_drop() {
@@ -532,6 +772,39 @@ mod tests {
         assert_eq!(itr.next(), None);
     }
     
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "read as Header, but holds Some(Element)")]
+    fn ring_header_mismatch() {
+        let ring = FramedRing::<i32>::new();
+        let frame = ring.frame();
+        frame.append_all(0..4);
+        let (frame_ro, _frame2) = frame.next();
+        assert_eq!(frame_ro.len(), 4);
+
+        // A frame pointing its start at one of the elements, as an off by one would. Neither is
+        // dropped, as dropping the bad frame would panic all over again.
+        let bad = ManuallyDrop::new(RingFrame {
+            ring: &ring,
+            start: frame_ro.start + 1,
+            live_at: frame_ro.live_at + 1,
+        });
+        std::mem::forget(frame_ro);
+        bad.len();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn ring_tags_bounded() {
+        let ring = FramedRing::<i32>::new();
+        for _ in 0..1000 {
+            let frame = ring.frame();
+            frame.append_all(0..4);
+        }
+        // Slots are reused frame after frame, so they shouldn't pile up tags either
+        assert!(ring.tags.borrow().len() <= ring.capacity());
+    }
+
     struct Dropchecker {
         dropped: *mut bool
     }
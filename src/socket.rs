@@ -16,17 +16,91 @@
  */
 
 use super::cursor;
+use super::mempool;
+use bytes::Buf;
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::Poll;
 use tokio::io::AsyncReadExt;
-use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::prelude::*;
+use tokio::time::{self, Instant};
+
+/// The ways a connection can fail, sorted so that callers can tell the other end going away apart
+/// from everything else
+#[derive(Debug)]
+pub enum SocketError {
+    Io(io::Error),
+    /// The other end hung up partway through something, like a frame
+    Eof,
+    /// Something didn't finish before its deadline
+    Timeout,
+    /// The other end has closed or reset the connection
+    Closed,
+}
+
+impl std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocketError::Io(e) => e.fmt(f),
+            SocketError::Eof => f.write_str("connection ended unexpectedly"),
+            SocketError::Timeout => f.write_str("connection timed out"),
+            SocketError::Closed => f.write_str("connection was closed by the other end"),
+        }
+    }
+}
+
+impl std::error::Error for SocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SocketError {
+    fn from(e: io::Error) -> SocketError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => SocketError::Eof,
+            io::ErrorKind::TimedOut => SocketError::Timeout,
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => SocketError::Closed,
+            _ => SocketError::Io(e),
+        }
+    }
+}
 
 pub trait BufferSource<T: cursor::DirectBufMut> {
     fn singlebuffer(&self) -> T;
-    //fn buffers(n: usize, vec: &mut VecDeque<T>);
+
+    fn buffers(&self, n: usize, vec: &mut VecDeque<T>) {
+        for _ in 0..n {
+            vec.push_back(self.singlebuffer());
+        }
+    }
+}
+
+impl BufferSource<bytes::BytesMut> for mempool::SystemMemPool {
+    fn singlebuffer(&self) -> bytes::BytesMut {
+        // Reads append to a BytesMut, so hand out an empty one with room to grow into
+        bytes::BytesMut::with_capacity(1 << self.buf_size)
+    }
 }
 
-pub struct ConnectionSource<'a> {
-    rh: ReadHalf<'a>,
+/// The most buffers read_adaptive will grow to by default
+pub const MAX_READ_BUFFERS: usize = 16;
+
+/// The read side of a connection - usually half of a TcpStream, but anything which can be read from
+/// will do
+pub struct ConnectionSource<R: AsyncRead + Unpin> {
+    rh: R,
+    read_buffers: usize,
+    max_read_buffers: usize,
+    // An error hit after read_adaptive had already read something, for the next read to return
+    read_error: Option<io::Error>,
 }
 
 pub enum ReadResult<T: cursor::DirectBufMut> {
@@ -34,34 +108,540 @@ pub enum ReadResult<T: cursor::DirectBufMut> {
     EOF,
 }
 
-impl<'a> ConnectionSource<'a> {
+impl<R: AsyncRead + Unpin> ConnectionSource<R> {
+    pub fn new(rh: R) -> ConnectionSource<R> {
+        ConnectionSource {
+            rh,
+            read_buffers: 1,
+            max_read_buffers: MAX_READ_BUFFERS,
+            read_error: None,
+        }
+    }
+
+    /// Caps how many buffers read_adaptive will try to fill in one go
+    pub fn set_max_read_buffers(&mut self, max_read_buffers: usize) {
+        assert!(max_read_buffers > 0);
+        self.max_read_buffers = max_read_buffers;
+        self.read_buffers = std::cmp::min(self.read_buffers, max_read_buffers);
+    }
+
+    /// How many buffers the next read_adaptive will try to fill
+    pub fn read_buffers(&self) -> usize {
+        self.read_buffers
+    }
+
     pub async fn read<T: cursor::DirectBufMut, BS: BufferSource<T>>(
         &mut self,
         alloc: &BS,
     ) -> io::Result<ReadResult<T>> {
         let mut buf = alloc.singlebuffer();
 
-        let amount_read = self.rh.read_buf(&mut buf).await?;
+        let amount_read = self.read_into(&mut buf).await?;
 
         if amount_read == 0 {
             // The other side hung up... what do we do here? This is a close
             Ok(ReadResult::EOF)
         } else {
-            buf.truncate(amount_read);
+            if let Err(len) = buf.try_truncate(amount_read) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("read {} bytes into a buffer of {}", amount_read, len),
+                ));
+            }
             Ok(ReadResult::Data(buf))
         }
     }
+
+    /// Reads into a buffer owned by the caller, returning how much was read. Unlike read, this is
+    /// safe to cancel - if the future is dropped before completing nothing has been read, and the
+    /// buffer (along with anything already in it) is still with the caller.
+    pub async fn read_into<B: bytes::BufMut>(&mut self, buf: &mut B) -> io::Result<usize> {
+        if let Some(e) = self.read_error.take() {
+            return Err(e);
+        }
+        self.rh.read_buf(buf).await
+    }
+
+    /// Reads whatever the socket has ready into as many buffers as recent reads suggest it will
+    /// have, appending the ones that got data to out and returning how much was read. 0 means the
+    /// other side hung up. Only the first buffer waits for data - the rest are filled for as long
+    /// as the socket has more to give without blocking. An error hit after some data was read is
+    /// returned by the next read instead.
+    ///
+    /// Every time all the buffers are filled the next read asks for twice as many, up to the cap,
+    /// and whenever a read needed at most half of them the next asks for half as many.
+    pub async fn read_adaptive<T: cursor::DirectBufMut, BS: BufferSource<T>>(
+        &mut self,
+        alloc: &BS,
+        out: &mut VecDeque<T>,
+    ) -> io::Result<usize> {
+        let mut bufs = VecDeque::with_capacity(self.read_buffers);
+        alloc.buffers(self.read_buffers, &mut bufs);
+
+        let mut total = 0;
+        let mut used = 0;
+        let mut filled = true;
+        for buf in bufs.iter_mut() {
+            let room = buf.bytes_mut().len();
+            let amount_read = if used == 0 {
+                self.read_into(buf).await?
+            } else {
+                // Errors are held for the next read to return so that what was read isn't lost
+                match self.try_read_into(buf).await {
+                    Some(Ok(n)) => n,
+                    Some(Err(e)) => {
+                        self.read_error = Some(e);
+                        0
+                    }
+                    None => 0,
+                }
+            };
+            if amount_read == 0 {
+                filled = false;
+                break;
+            }
+
+            if let Err(len) = buf.try_truncate(amount_read) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("read {} bytes into a buffer of {}", amount_read, len),
+                ));
+            }
+            total += amount_read;
+            used += 1;
+            if amount_read < room {
+                filled = false;
+                break;
+            }
+        }
+
+        if filled {
+            self.read_buffers = std::cmp::min(self.read_buffers * 2, self.max_read_buffers);
+        } else if used * 2 <= self.read_buffers {
+            self.read_buffers = std::cmp::max(self.read_buffers / 2, 1);
+        }
+
+        out.extend(bufs.into_iter().take(used));
+        Ok(total)
+    }
+
+    /// Reads into buf only if the socket has data ready right now, returning None if it doesn't
+    async fn try_read_into<B: bytes::BufMut>(&mut self, buf: &mut B) -> Option<io::Result<usize>> {
+        let rh = &mut self.rh;
+        tokio::future::poll_fn(|cx| match Pin::new(&mut *rh).poll_read_buf(cx, buf) {
+            Poll::Ready(r) => Poll::Ready(Some(r)),
+            Poll::Pending => Poll::Ready(None),
+        })
+        .await
+    }
+}
+
+/// The most IoSlices a single vectored write will be handed by default. Linux and the BSDs all
+/// have an IOV_MAX of 1024. Note that tokio's TcpStream never asks for more than 64 slices at a
+/// time anyway, so over TCP this default has no effect - only caps below 64 do.
+pub const MAX_IO_SLICES: usize = 1024;
+
+/// Wraps a Buf so that bytes_vectored never fills in more than max_slices slices - a writev of a
+/// Multibytes with many pages is then split over several calls, each within the limit.
+pub struct SliceCapped<B: bytes::Buf> {
+    inner: B,
+    max_slices: usize,
 }
 
-pub struct ConnectionSink<'a> {
-    wh: WriteHalf<'a>,
+impl<B: bytes::Buf> SliceCapped<B> {
+    pub fn new(inner: B, max_slices: usize) -> SliceCapped<B> {
+        assert!(max_slices > 0);
+        SliceCapped { inner, max_slices }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
 }
 
-impl<'a> ConnectionSink<'a> {
-    pub async fn write<T: bytes::Buf>(&mut self, mut buf: T) -> io::Result<()> {
+impl<B: bytes::Buf> bytes::Buf for SliceCapped<B> {
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.inner.bytes()
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.inner.advance(cnt)
+    }
+
+    fn bytes_vectored<'b>(&'b self, dst: &mut [IoSlice<'b>]) -> usize {
+        let len = std::cmp::min(dst.len(), self.max_slices);
+        self.inner.bytes_vectored(&mut dst[..len])
+    }
+}
+
+/// The write side of a connection, which like ConnectionSource can be anything which can be
+/// written to
+pub struct ConnectionSink<W: AsyncWrite + Unpin> {
+    wh: W,
+    max_io_slices: usize,
+}
+
+impl<W: AsyncWrite + Unpin> ConnectionSink<W> {
+    pub fn new(wh: W) -> ConnectionSink<W> {
+        ConnectionSink {
+            wh,
+            max_io_slices: MAX_IO_SLICES,
+        }
+    }
+
+    /// Caps the number of slices handed to any one vectored write
+    pub fn set_max_io_slices(&mut self, max_io_slices: usize) {
+        assert!(max_io_slices > 0);
+        self.max_io_slices = max_io_slices;
+    }
+
+    pub async fn write<T: bytes::Buf>(&mut self, buf: T) -> io::Result<()> {
+        let mut buf = SliceCapped::new(buf, self.max_io_slices);
         while buf.has_remaining() {
             self.wh.write_buf(&mut buf).await?;
         }
         Ok(())
     }
+
+    /// Like write, but gives up with TimedOut if the whole buffer can't be written by the deadline.
+    /// Pass the buffer by &mut to find out how much was left unwritten when this fails.
+    pub async fn write_deadline<T: bytes::Buf>(
+        &mut self,
+        buf: T,
+        deadline: Instant,
+    ) -> io::Result<()> {
+        let mut buf = SliceCapped::new(buf, self.max_io_slices);
+        while buf.has_remaining() {
+            match time::timeout_at(deadline, self.wh.write_buf(&mut buf)).await {
+                Ok(r) => r?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "write did not finish before the deadline",
+                    ))
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use std::future::Future;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn socket_error_kinds() {
+        let from = |kind| SocketError::from(io::Error::new(kind, "test"));
+        assert!(matches!(
+            from(io::ErrorKind::UnexpectedEof),
+            SocketError::Eof
+        ));
+        assert!(matches!(
+            from(io::ErrorKind::TimedOut),
+            SocketError::Timeout
+        ));
+        assert!(matches!(
+            from(io::ErrorKind::BrokenPipe),
+            SocketError::Closed
+        ));
+        assert!(matches!(
+            from(io::ErrorKind::ConnectionReset),
+            SocketError::Closed
+        ));
+        match from(io::ErrorKind::PermissionDenied) {
+            SocketError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(SocketError::Timeout.to_string(), "connection timed out");
+    }
+
+    #[test]
+    fn read_into_cancelled() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let (rh, _) = server.split();
+            let mut source = ConnectionSource::new(rh);
+
+            // Left over from an earlier read
+            let mut buf = bytes::BytesMut::with_capacity(64);
+            buf.put_slice(&[1, 2]);
+
+            {
+                // Nothing has been sent yet, so this can't finish before being cancelled
+                let mut read = Box::pin(source.read_into(&mut buf));
+                let finished =
+                    tokio::future::poll_fn(|cx| Poll::Ready(read.as_mut().poll(cx).is_ready()))
+                        .await;
+                assert!(!finished);
+            }
+
+            client.write_all(&[3, 4]).await.unwrap();
+            assert_eq!(source.read_into(&mut buf).await.unwrap(), 2);
+            assert_eq!(&buf[..], [1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn write_deadline() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let (_, wh) = server.split();
+            let mut sink = ConnectionSink::new(wh);
+
+            let deadline = Instant::now() + std::time::Duration::from_secs(5);
+            sink.write_deadline(&[1, 2, 3][..], deadline).await.unwrap();
+            let mut small = [0; 3];
+            client.read_exact(&mut small).await.unwrap();
+            assert_eq!(small, [1, 2, 3]);
+
+            // Nobody is reading on the other end, so this will fill up the socket buffers and stall
+            let data = vec![0; 64 << 20];
+            let mut buf = &data[..];
+            let deadline = Instant::now() + std::time::Duration::from_millis(50);
+            let e = sink.write_deadline(&mut buf, deadline).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            assert!(Instant::now() >= deadline);
+
+            // Some of it got out, but what didn't is still there to be written
+            assert!(buf.len() > 0 && buf.len() < data.len());
+        });
+    }
+
+    fn tiny_pages(n: usize) -> cursor::Multibytes<bytes::Bytes> {
+        let mut mb = cursor::Multibytes::new(std::collections::VecDeque::new());
+        for i in 0..n {
+            mb.append(bytes::Bytes::from(vec![i as u8]));
+        }
+        mb
+    }
+
+    #[test]
+    fn slice_capped() {
+        let mut capped = SliceCapped::new(tiny_pages(2000), 100);
+        let mut calls = 0;
+        while capped.has_remaining() {
+            let mut dst = [IoSlice::new(&[]); 2048];
+            let n = capped.bytes_vectored(&mut dst);
+            assert!(n <= 100);
+            let len: usize = dst[..n].iter().map(|s| s.len()).sum();
+            capped.advance(len);
+            calls += 1;
+        }
+        assert_eq!(calls, 20);
+
+        // A single slice at a time, even though every page could fit
+        let mut capped = SliceCapped::new(tiny_pages(3), 1);
+        for i in 0..3 {
+            let mut dst = [IoSlice::new(&[]); 4];
+            assert_eq!(capped.bytes_vectored(&mut dst), 1);
+            assert_eq!(&dst[0][..], &[i as u8]);
+            capped.advance(1);
+        }
+        assert!(!capped.has_remaining());
+    }
+
+    #[test]
+    fn write_capped() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let (_, wh) = server.split();
+            let mut sink = ConnectionSink::new(wh);
+            // Below the 64 slices tokio would hand over on its own
+            sink.set_max_io_slices(16);
+
+            sink.write(tiny_pages(2000)).await.unwrap();
+            let mut recv = vec![0; 2000];
+            client.read_exact(&mut recv).await.unwrap();
+            for (i, b) in recv.iter().enumerate() {
+                assert_eq!(*b, i as u8);
+            }
+        });
+    }
+
+    #[test]
+    fn read_adaptive() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let (rh, _) = server.split();
+            let mut source = ConnectionSource::new(rh);
+            source.set_max_read_buffers(8);
+            let alloc = mempool::SystemMemPool { buf_size: 10 };
+
+            // Keep the socket stocked with far more than one buffer's worth
+            let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+            let writer = tokio::spawn(async move {
+                client.write_all(&data).await.unwrap();
+                client
+            });
+
+            let mut recv = VecDeque::new();
+            let mut total = 0;
+            let mut reads = 0;
+            let mut most_buffers = 0;
+            while total < 1 << 20 {
+                let before = recv.len();
+                let n = source.read_adaptive(&alloc, &mut recv).await.unwrap();
+                assert!(n > 0);
+                assert!(recv.len() - before <= 8);
+                most_buffers = std::cmp::max(most_buffers, recv.len() - before);
+                total += n;
+                reads += 1;
+            }
+            assert_eq!(total, 1 << 20);
+            let _client = writer.await.unwrap();
+
+            // Reading a buffer at a time would have taken 1024 reads
+            assert!(most_buffers > 1);
+            assert!(reads < recv.len());
+            assert!(recv.iter().all(|b| b.len() <= 1 << 10));
+            let mut i = 0usize;
+            for b in recv.iter() {
+                for x in b.iter() {
+                    assert_eq!(*x, i as u8);
+                    i += 1;
+                }
+            }
+        });
+    }
+
+    // Hands out each of its reads in turn, then hangs up
+    struct ScriptedReader(VecDeque<io::Result<Vec<u8>>>);
+
+    impl AsyncRead for ScriptedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(match self.0.pop_front() {
+                Some(Ok(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            })
+        }
+    }
+
+    #[test]
+    fn read_adaptive_error() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let alloc = mempool::SystemMemPool { buf_size: 4 };
+            let mut source = ConnectionSource::new(ScriptedReader(VecDeque::from(vec![
+                Ok(vec![1; 16]),
+                Ok(vec![2; 16]),
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+            ])));
+
+            let mut recv = VecDeque::new();
+            assert_eq!(source.read_adaptive(&alloc, &mut recv).await.unwrap(), 16);
+            assert_eq!(source.read_buffers(), 2);
+            // The reset comes up while filling the second buffer, after the first got data
+            assert_eq!(source.read_adaptive(&alloc, &mut recv).await.unwrap(), 16);
+            assert_eq!(recv.len(), 2);
+
+            // Rather than being lost, and the reader seeing a clean hang up
+            let err = source.read_adaptive(&alloc, &mut recv).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(source.read_adaptive(&alloc, &mut recv).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn in_memory() {
+        use crate::framer::Framer;
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let mut sink = ConnectionSink::new(client);
+            let mut source = ConnectionSource::new(server);
+            let alloc = mempool::SystemMemPool { buf_size: 4 };
+
+            let mut frames = cursor::Multibytes::from_buf(bytes::Bytes::from_static(&[0x2, 1, 2]));
+            frames.append(bytes::Bytes::from_static(&[0x3, 3, 4, 5]));
+            sink.write(&mut frames).await.unwrap();
+            drop(sink);
+
+            let mut framer = Framer::new(64, 4);
+            while let ReadResult::Data(b) = source.read(&alloc).await.unwrap() {
+                framer.push_buffer(b);
+            }
+
+            let mut body = |len| {
+                let frame = framer.frame().unwrap();
+                assert_eq!(frame.payload_len(), len);
+                frame.into_body().to_bytes()
+            };
+            assert_eq!(body(2), &[1, 2][..]);
+            assert_eq!(body(3), &[3, 4, 5][..]);
+            assert!(framer.is_idle());
+        });
+    }
 }
@@ -0,0 +1,753 @@
+/*
+ *  Copyright (C) 2020  Joe Hirschfeld <j@ibj.io>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::crypto::Cryptor;
+use super::cursor;
+use super::framer;
+use super::inflater;
+use super::mempool;
+use super::protocol;
+use super::socket;
+use crate::zlib;
+
+use bytes::Buf;
+use std::collections::VecDeque;
+use tokio::io;
+
+#[derive(Debug, PartialEq)]
+pub enum StreamError {
+    /// The framer hit something it couldn't decode. This is fatal.
+    FrameError(framer::FrameError),
+    InflaterError(inflater::InflaterError),
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::FrameError(e) => e.fmt(f),
+            StreamError::InflaterError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::FrameError(e) => Some(e),
+            StreamError::InflaterError(e) => Some(e),
+        }
+    }
+}
+
+impl From<framer::FrameError> for StreamError {
+    fn from(f: framer::FrameError) -> StreamError {
+        StreamError::FrameError(f)
+    }
+}
+
+impl From<inflater::InflaterError> for StreamError {
+    fn from(i: inflater::InflaterError) -> StreamError {
+        StreamError::InflaterError(i)
+    }
+}
+
+impl From<StreamError> for io::Error {
+    fn from(e: StreamError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl From<StreamError> for socket::SocketError {
+    fn from(e: StreamError) -> socket::SocketError {
+        socket::SocketError::Io(e.into())
+    }
+}
+
+/// Running totals of what has passed through one half of a connection. A PacketStream only counts
+/// the inbound fields and a PacketSink only the outbound ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineStats {
+    /// Bytes pushed in off the connection
+    pub bytes_in: u64,
+    pub frames_in: u64,
+    /// How many of frames_in were compressed, and how large they were before and after inflating
+    pub compressed_frames_in: u64,
+    pub compressed_bytes_in: u64,
+    pub inflated_bytes_in: u64,
+    /// Frames which could not be framed or inflated
+    pub decode_errors: u64,
+    pub packets_out: u64,
+    /// Bytes written out to the connection
+    pub bytes_out: u64,
+    pub flushes: u64,
+}
+
+impl PipelineStats {
+    /// How many times smaller compressed frames were than what they inflated to, or None if there
+    /// haven't been any
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.compressed_bytes_in == 0 {
+            None
+        } else {
+            Some(self.inflated_bytes_in as f64 / self.compressed_bytes_in as f64)
+        }
+    }
+}
+
+/// The inbound half of a connection - buffers are decrypted as they are pushed in, then framed and
+/// inflated into packets.
+pub struct PacketStream<T: cursor::DirectBufMut> {
+    crypto: Cryptor,
+    framer: framer::Framer<T>,
+    inflater: inflater::PacketInflater,
+    stats: PipelineStats,
+}
+
+impl<T: cursor::DirectBufMut> PacketStream<T> {
+    pub fn new(max_frame_size: usize, buffer_size: usize) -> Self {
+        PacketStream {
+            crypto: Cryptor::new_decrypt(),
+            framer: framer::Framer::new(max_frame_size, buffer_size),
+            inflater: inflater::PacketInflater::new(),
+            stats: PipelineStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats
+    }
+
+    pub fn push_buffer(&mut self, mut b: T) {
+        self.stats.bytes_in += b.remaining() as u64;
+        self.crypto.process(b.as_mut());
+        self.framer.push_buffer(b);
+    }
+
+    /// Produces the next packet out of the data which has already been pushed, or None if more
+    /// data is needed to finish the next frame.
+    pub fn next_buffered<'a, Alloc: mempool::BlockAllocator<'a, T>>(
+        &mut self,
+        alloc: &'a Alloc,
+    ) -> Result<Option<inflater::Packet<T>>, StreamError> {
+        match self.framer.frame() {
+            Ok(frame) => {
+                self.stats.frames_in += 1;
+                let wire_len = frame.wire_len();
+                let packet = match self.inflater.inflate(frame, alloc) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        self.stats.decode_errors += 1;
+                        return Err(e.into());
+                    }
+                };
+                if let inflater::DataBacking::Multibytes(mb) = &packet.d {
+                    // What is left in the header is the frame and data length headers
+                    self.stats.compressed_frames_in += 1;
+                    self.stats.compressed_bytes_in += (wire_len - packet.h.remaining()) as u64;
+                    self.stats.inflated_bytes_in += mb.remaining() as u64;
+                }
+                Ok(Some(packet))
+            }
+            Err(framer::FrameError::WaitingForHeader)
+            | Err(framer::FrameError::WaitingForData(_)) => Ok(None),
+            Err(e) => {
+                self.stats.decode_errors += 1;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Frames and inflates every complete packet which is currently buffered, without waiting on
+    /// any more data. This should be called before reconfiguring the stream so that everything
+    /// already received is handled under the old configuration.
+    pub fn drain_buffered<'a, Alloc: mempool::BlockAllocator<'a, T>>(
+        &mut self,
+        alloc: &'a Alloc,
+    ) -> Result<Vec<inflater::Packet<T>>, StreamError> {
+        let mut packets = Vec::new();
+        while let Some(p) = self.next_buffered(alloc)? {
+            packets.push(p);
+        }
+        Ok(packets)
+    }
+
+    /// Reads from the source until a full packet is available. Ok(None) is only returned if the
+    /// other end hung up on a packet boundary - hanging up in the middle of a frame is an Eof
+    /// error.
+    pub async fn next_packet<
+        'a,
+        BS: socket::BufferSource<T>,
+        Alloc: mempool::BlockAllocator<'a, T>,
+        R: io::AsyncRead + Unpin,
+    >(
+        &mut self,
+        source: &mut socket::ConnectionSource<R>,
+        buffers: &BS,
+        alloc: &'a Alloc,
+    ) -> Result<Option<inflater::Packet<T>>, socket::SocketError> {
+        loop {
+            if let Some(p) = self.next_buffered(alloc)? {
+                return Ok(Some(p));
+            }
+
+            match source.read(buffers).await? {
+                socket::ReadResult::Data(b) => self.push_buffer(b),
+                socket::ReadResult::EOF => {
+                    if self.framer.is_idle() {
+                        return Ok(None);
+                    }
+                    return Err(socket::SocketError::Eof);
+                }
+            }
+        }
+    }
+
+    /// Switches to reading packets with the compression header, as after a Set Compression packet.
+    /// Frames are only inflated as they are taken out of the stream, so this applies from the next
+    /// packet taken out on - including any already pushed in behind the one which asked for it.
+    pub fn enable_compression(&mut self, threshold: i32) -> Result<(), zlib::ZLibError> {
+        self.inflater.start_compression(threshold)
+    }
+
+    /// Switches back to reading packets without the compression header. Like enable_compression,
+    /// this applies from the next packet taken out on.
+    pub fn disable_compression(&mut self) {
+        self.inflater.stop_compression()
+    }
+
+    /// The threshold packets are being read with, or None if compression isn't enabled
+    pub fn compression_threshold(&self) -> Option<i32> {
+        self.inflater.compression_threshold()
+    }
+
+    /// Caps the total bytes inflated over this connection, see
+    /// PacketInflater::set_max_total_inflated
+    pub fn set_max_total_inflated(&mut self, max: Option<usize>) {
+        self.inflater.set_max_total_inflated(max)
+    }
+
+    /// Decrypts everything from the next packet on. Anything already buffered behind the packet
+    /// which asked for this is decrypted straight away, as it arrived encrypted.
+    pub fn start_crypto(&mut self, key: [u8; 16]) {
+        self.crypto.start_crypto(key);
+        let crypto = &mut self.crypto;
+        self.framer.for_each_unframed_mut(|b| crypto.process(b));
+    }
+
+    /// Packets produced after this are marked with the new state. Like compression, anything
+    /// already buffered which belongs to the old state should be drained first.
+    pub fn set_state(&mut self, state: protocol::ProtocolState) {
+        self.inflater.set_state(state)
+    }
+
+    pub fn state(&self) -> protocol::ProtocolState {
+        self.inflater.state()
+    }
+}
+
+/// How a PacketSink decides when to write out the packets sent through it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchMode {
+    /// Every packet is written out as soon as it is sent
+    Immediate,
+    /// Packets are held until either limit is reached or flush is called, then all written out
+    /// together
+    Coalesce {
+        max_packets: usize,
+        max_bytes: usize,
+    },
+}
+
+/// The outbound half of a connection - frames are encrypted as they are sent, then written out as
+/// the BatchMode dictates.
+pub struct PacketSink<T: cursor::DirectBufMut> {
+    crypto: Cryptor,
+    mode: BatchMode,
+    pending: cursor::Multibytes<T>,
+    pending_packets: usize,
+    pending_bytes: usize,
+    stats: PipelineStats,
+}
+
+impl<T: cursor::DirectBufMut> PacketSink<T> {
+    pub fn new(mode: BatchMode, buffer_size: usize) -> Self {
+        PacketSink {
+            crypto: Cryptor::new_encrypt(),
+            mode,
+            pending: cursor::Multibytes::new(VecDeque::with_capacity(buffer_size)),
+            pending_packets: 0,
+            pending_bytes: 0,
+            stats: PipelineStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats
+    }
+
+    /// The number of packets sent which haven't been written out yet
+    pub fn pending_packets(&self) -> usize {
+        self.pending_packets
+    }
+
+    /// Sends a frame, which must already have its length header. Depending on the BatchMode, this
+    /// may not be written out until a later send or flush.
+    pub async fn send_packet<W: io::AsyncWrite + Unpin>(
+        &mut self,
+        sink: &mut socket::ConnectionSink<W>,
+        packet: cursor::Multibytes<T>,
+    ) -> Result<(), socket::SocketError> {
+        for mut page in packet {
+            self.crypto.process(page.as_mut());
+            self.pending_bytes += page.remaining();
+            self.pending.append(page);
+        }
+        self.pending_packets += 1;
+
+        let full = match self.mode {
+            BatchMode::Immediate => true,
+            BatchMode::Coalesce {
+                max_packets,
+                max_bytes,
+            } => self.pending_packets >= max_packets || self.pending_bytes >= max_bytes,
+        };
+
+        if full {
+            self.flush(sink).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes out every packet which has been sent so far. If the write fails, whatever didn't
+    /// make it out is dropped - the connection is left partway through a packet, so there is no
+    /// picking back up from there.
+    pub async fn flush<W: io::AsyncWrite + Unpin>(
+        &mut self,
+        sink: &mut socket::ConnectionSink<W>,
+    ) -> Result<(), socket::SocketError> {
+        let result = sink.write(&mut self.pending).await;
+        // Writing consumes pending as it goes, so what is left is what didn't get written
+        self.stats.bytes_out += (self.pending_bytes - self.pending.remaining()) as u64;
+        let packets = self.pending_packets;
+        self.pending.clear();
+        self.pending_packets = 0;
+        self.pending_bytes = 0;
+
+        result?;
+        self.stats.packets_out += packets as u64;
+        self.stats.flushes += 1;
+        Ok(())
+    }
+
+    /// Only packets sent after this call will be encrypted.
+    pub fn start_crypto(&mut self, key: [u8; 16]) {
+        self.crypto.start_crypto(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter::FromIterator;
+
+    macro_rules! to_buf {
+        ($x: expr) => {
+            bytes::BytesMut::from_iter($x.iter())
+        };
+    }
+
+    #[test]
+    fn drain_before_compression() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut stream = PacketStream::new(128, 4);
+
+        // Two uncompressed frames, the second being split over two buffers
+        stream.push_buffer(to_buf!([0x4, 0x1, 0x0, 0x1, 0x2, 0x3, 0x0]));
+        stream.push_buffer(to_buf!([0x1, 0x2]));
+
+        let packets = stream.drain_buffered(&alloc).unwrap();
+        assert_eq!(packets.len(), 2);
+        for (p, len) in packets.iter().zip([4, 3].iter()) {
+            if let inflater::DataBacking::Cursor(c) = p.d {
+                assert_eq!(c.remaining(&p.h), *len);
+            } else {
+                panic!("non-cursor");
+            }
+        }
+        assert!(stream.drain_buffered(&alloc).unwrap().is_empty());
+
+        stream.enable_compression(3).unwrap();
+        stream.push_buffer(to_buf!([
+            13, 0x4, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11
+        ]));
+
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        if let inflater::DataBacking::Multibytes(mb) = packet.d {
+            let mut view = mb.view();
+            assert_eq!(view.get_u8(), 0x1);
+            assert_eq!(view.get_u8(), 0x2);
+            assert_eq!(view.get_u8(), 0x3);
+            assert_eq!(view.get_u8(), 0x4);
+            assert_eq!(view.remaining(), 0);
+        } else {
+            panic!("non-mb");
+        }
+        assert!(stream.next_buffered(&alloc).unwrap().is_none());
+    }
+
+    #[test]
+    fn toggle_compression() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut stream = PacketStream::new(128, 4);
+        assert_eq!(stream.compression_threshold(), None);
+
+        // An uncompressed packet, with a compressed one right behind it in the same buffer
+        stream.push_buffer(to_buf!([
+            0x2, 0x3, 0x3, 13, 0x4, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11
+        ]));
+
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        if let inflater::DataBacking::Cursor(c) = packet.d {
+            assert_eq!(packet.h.cursor_view(c).to_bytes(), &[0x3, 0x3][..]);
+        } else {
+            panic!("non-cursor");
+        }
+
+        stream.enable_compression(3).unwrap();
+        assert_eq!(stream.compression_threshold(), Some(3));
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        if let inflater::DataBacking::Multibytes(mut mb) = packet.d {
+            assert_eq!(mb.to_bytes(), &[0x1, 0x2, 0x3, 0x4][..]);
+        } else {
+            panic!("non-mb");
+        }
+
+        // Without compression, the data length header isn't split off anymore
+        stream.disable_compression();
+        assert_eq!(stream.compression_threshold(), None);
+        stream.push_buffer(to_buf!([0x2, 0x0, 0x7]));
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        if let inflater::DataBacking::Cursor(c) = packet.d {
+            assert_eq!(packet.h.cursor_view(c).to_bytes(), &[0x0, 0x7][..]);
+        } else {
+            panic!("non-cursor");
+        }
+    }
+
+    #[test]
+    fn crypto_mid_buffer() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut stream = PacketStream::new(128, 4);
+        let key = [7; 16];
+
+        let mut encrypted = [0x2, 0x0, 0x7, 0x1, 0x5];
+        let mut encryptor = Cryptor::new_encrypt();
+        encryptor.start_crypto(key);
+        encryptor.process(&mut encrypted);
+
+        // The last plaintext packet, with the start of the encrypted ones right behind it
+        let mut first = vec![0x2, 0x3, 0x3];
+        first.extend_from_slice(&encrypted[..3]);
+        stream.push_buffer(to_buf!(first));
+
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        if let inflater::DataBacking::Cursor(c) = packet.d {
+            assert_eq!(packet.h.cursor_view(c).to_bytes(), &[0x3, 0x3][..]);
+        } else {
+            panic!("non-cursor");
+        }
+
+        stream.start_crypto(key);
+        stream.push_buffer(to_buf!(encrypted[3..]));
+        for body in [&[0x0, 0x7][..], &[0x5][..]].iter() {
+            let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+            if let inflater::DataBacking::Cursor(c) = packet.d {
+                assert_eq!(packet.h.cursor_view(c).to_bytes(), body);
+            } else {
+                panic!("non-cursor");
+            }
+        }
+        assert!(stream.next_buffered(&alloc).unwrap().is_none());
+    }
+
+    #[test]
+    fn protocol_state() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut stream = PacketStream::new(128, 4);
+        assert_eq!(stream.state(), protocol::ProtocolState::Handshaking);
+
+        stream.push_buffer(to_buf!([0x2, 0x0, 0x2, 0x1, 0x0]));
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        assert_eq!(packet.state, protocol::ProtocolState::Handshaking);
+
+        stream.set_state(protocol::ProtocolState::Play);
+        assert_eq!(stream.state(), protocol::ProtocolState::Play);
+        let packet = stream.next_buffered(&alloc).unwrap().unwrap();
+        assert_eq!(packet.state, protocol::ProtocolState::Play);
+    }
+
+    #[test]
+    fn stream_stats() {
+        let alloc = mempool::SystemMemPool { buf_size: 12 };
+        let mut stream = PacketStream::new(128, 4);
+        assert_eq!(stream.stats().compression_ratio(), None);
+
+        stream.push_buffer(to_buf!([0x4, 0x1, 0x0, 0x1, 0x2, 0x3, 0x0]));
+        stream.push_buffer(to_buf!([0x1, 0x2]));
+        assert_eq!(stream.drain_buffered(&alloc).unwrap().len(), 2);
+
+        stream.enable_compression(3).unwrap();
+        stream.push_buffer(to_buf!([
+            13, 0x4, 120, 156, 99, 100, 98, 102, 1, 0, 0, 24, 0, 11
+        ]));
+        stream.next_buffered(&alloc).unwrap().unwrap();
+
+        // Declares 2 bytes of data, under the threshold of 3
+        stream.push_buffer(to_buf!([0x3, 0x2, 0x0, 0x0]));
+        assert!(stream.next_buffered(&alloc).is_err());
+
+        let stats = stream.stats();
+        assert_eq!(stats.bytes_in, 9 + 14 + 4);
+        assert_eq!(stats.frames_in, 4);
+        assert_eq!(stats.compressed_frames_in, 1);
+        assert_eq!(stats.compressed_bytes_in, 12);
+        assert_eq!(stats.inflated_bytes_in, 4);
+        assert_eq!(stats.decode_errors, 1);
+        assert_eq!(stats.compression_ratio(), Some(4.0 / 12.0));
+        assert_eq!(stats.packets_out, 0);
+    }
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Sends data over a real connection, hangs up, then collects everything next_packet produces
+    fn read_all_packets(data: Vec<u8>) -> Result<usize, socket::SocketError> {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            client.write_all(&data).await.unwrap();
+            drop(client);
+
+            let (rh, _) = server.split();
+            let mut source = socket::ConnectionSource::new(rh);
+            let alloc = mempool::SystemMemPool { buf_size: 12 };
+            let mut stream = PacketStream::new(128, 4);
+
+            let mut count = 0;
+            while let Some(_) = stream.next_packet(&mut source, &alloc, &alloc).await? {
+                count += 1;
+            }
+            Ok(count)
+        })
+    }
+
+    #[test]
+    fn eof_on_boundary() {
+        assert_eq!(
+            read_all_packets(vec![0x2, 0x0, 0x1, 0x3, 0x0, 0x1, 0x2]).unwrap(),
+            2
+        );
+        assert_eq!(read_all_packets(vec![]).unwrap(), 0);
+    }
+
+    #[test]
+    fn eof_mid_frame() {
+        let e = read_all_packets(vec![0x2, 0x0, 0x1, 0x3, 0x0]).unwrap_err();
+        assert!(matches!(e, socket::SocketError::Eof));
+
+        // Cutting off in the middle of a header is also a truncation
+        let e = read_all_packets(vec![0x2, 0x0, 0x1, 0x80]).unwrap_err();
+        assert!(matches!(e, socket::SocketError::Eof));
+    }
+
+    #[test]
+    fn undecodable_frame() {
+        let e = read_all_packets(vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x2]).unwrap_err();
+        match e {
+            socket::SocketError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn send_after_close() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            drop(client);
+
+            let (_, wh) = server.split();
+            let mut sink = socket::ConnectionSink::new(wh);
+            let mut packets = PacketSink::new(BatchMode::Immediate, 4);
+
+            // The first writes may well land before the reset comes back
+            for sent in 0..100 {
+                let packet = cursor::Multibytes::from_buf(bytes::BytesMut::from(&[0x1, 0x0][..]));
+                match packets.send_packet(&mut sink, packet).await {
+                    Ok(()) => tokio::time::delay_for(std::time::Duration::from_millis(1)).await,
+                    Err(socket::SocketError::Closed) => {
+                        // The failed packet isn't left behind as pending, nor counted as sent
+                        assert_eq!(packets.pending_packets(), 0);
+                        assert_eq!(packets.stats().packets_out, sent);
+                        assert_eq!(packets.stats().flushes, sent);
+                        return;
+                    }
+                    Err(e) => panic!("unexpected {:?}", e),
+                }
+            }
+            panic!("writes never failed");
+        });
+    }
+
+    #[test]
+    fn coalesce() {
+        use std::time::Duration;
+        use tokio::io::AsyncReadExt;
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let (_, wh) = server.split();
+            let mut sink = socket::ConnectionSink::new(wh);
+
+            let mut packets = PacketSink::new(
+                BatchMode::Coalesce {
+                    max_packets: 3,
+                    max_bytes: 1024,
+                },
+                4,
+            );
+            let frame = |b: &[u8]| cursor::Multibytes::from_buf(bytes::BytesMut::from(b));
+
+            packets
+                .send_packet(&mut sink, frame(&[0x1, 0xa]))
+                .await
+                .unwrap();
+            packets
+                .send_packet(&mut sink, frame(&[0x2, 0xb, 0xb]))
+                .await
+                .unwrap();
+            assert_eq!(packets.pending_packets(), 2);
+
+            // Nothing should have been written yet
+            let mut recv = [0; 8];
+            let waited =
+                tokio::time::timeout(Duration::from_millis(20), client.read(&mut recv)).await;
+            assert!(waited.is_err());
+
+            // The third packet fills the batch
+            packets
+                .send_packet(&mut sink, frame(&[0x1, 0xc]))
+                .await
+                .unwrap();
+            assert_eq!(packets.pending_packets(), 0);
+            client.read_exact(&mut recv[..7]).await.unwrap();
+            assert_eq!(&recv[..7], [0x1, 0xa, 0x2, 0xb, 0xb, 0x1, 0xc]);
+
+            packets
+                .send_packet(&mut sink, frame(&[0x1, 0xd]))
+                .await
+                .unwrap();
+            packets.flush(&mut sink).await.unwrap();
+            client.read_exact(&mut recv[..2]).await.unwrap();
+            assert_eq!(&recv[..2], [0x1, 0xd]);
+
+            let stats = packets.stats();
+            assert_eq!(stats.packets_out, 4);
+            assert_eq!(stats.bytes_out, 9);
+            assert_eq!(stats.flushes, 2);
+            assert_eq!(stats.bytes_in, 0);
+        });
+    }
+
+    #[test]
+    fn duplex_round_trip() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let mut sink = socket::ConnectionSink::new(client);
+            let mut source = socket::ConnectionSource::new(server);
+            let alloc = mempool::SystemMemPool { buf_size: 12 };
+
+            let key = [7; 16];
+            let mut packets = PacketSink::new(BatchMode::Immediate, 4);
+            packets.start_crypto(key);
+            let mut stream = PacketStream::new(128, 4);
+            stream.start_crypto(key);
+
+            let frame =
+                cursor::Multibytes::from_buf(bytes::BytesMut::from(&[0x3, 0x1, 0x2, 0x3][..]));
+            packets.send_packet(&mut sink, frame).await.unwrap();
+            drop(sink);
+
+            let packet = stream
+                .next_packet(&mut source, &alloc, &alloc)
+                .await
+                .unwrap()
+                .unwrap();
+            if let inflater::DataBacking::Cursor(c) = packet.d {
+                assert_eq!(packet.h.cursor_view(c).to_bytes(), &[0x1, 0x2, 0x3][..]);
+            } else {
+                panic!("non-cursor");
+            }
+            assert!(stream
+                .next_packet(&mut source, &alloc, &alloc)
+                .await
+                .unwrap()
+                .is_none());
+        });
+    }
+}
@@ -23,6 +23,14 @@ use std::mem::size_of;
 
 static ZLIB_MAJ_VERSION: &str = "1";
 
+// The only compression method zlib has, and the defaults deflateInit uses for the rest
+const Z_DEFLATED: c_int = 8;
+const DEFAULT_MEM_LEVEL: c_int = 8;
+const DEFAULT_STRATEGY: c_int = 0;
+
+// Not an error, so ZLibError::lookup passes it over
+const Z_STREAM_END: c_int = 1;
+
 #[repr(C)]
 pub struct ZStream {
     pub next_in: *const c_uchar,
@@ -57,15 +65,38 @@ extern "C" {
         version: *const c_char,
         stream_size: c_int,
     ) -> c_int;
+    fn deflateInit2_(
+        strm: *mut ZStream,
+        level: c_int,
+        method: c_int,
+        window_bits: c_int,
+        mem_level: c_int,
+        strategy: c_int,
+        version: *const c_char,
+        stream_size: c_int,
+    ) -> c_int;
+    fn inflateInit2_(
+        strm: *mut ZStream,
+        window_bits: c_int,
+        version: *const c_char,
+        stream_size: c_int,
+    ) -> c_int;
     fn inflateInit_(strm: *mut ZStream, version: *const c_char, stream_size: c_int) -> c_int;
 
     fn deflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn deflateCopy(dest: *mut ZStream, source: *mut ZStream) -> c_int;
+    fn deflatePending(strm: *mut ZStream, pending: *mut c_uint, bits: *mut c_int) -> c_int;
+    fn deflateBound(strm: *mut ZStream, source_len: c_ulong) -> c_ulong;
     fn deflateEnd(strm: *mut ZStream) -> c_int;
     fn inflate(strm: *mut ZStream, flush: c_int) -> c_int;
     fn inflateEnd(strm: *mut ZStream) -> c_int;
+    fn inflateCopy(dest: *mut ZStream, source: *mut ZStream) -> c_int;
 
     fn deflateReset(strm: *mut ZStream);
     fn inflateReset(sterm: *mut ZStream);
+
+    fn adler32(adler: c_ulong, buf: *const c_uchar, len: c_uint) -> c_ulong;
+    fn crc32(crc: c_ulong, buf: *const c_uchar, len: c_uint) -> c_ulong;
 }
 
 #[repr(i32)]
@@ -92,6 +123,22 @@ impl ZLibError {
     }
 }
 
+impl std::fmt::Display for ZLibError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ZLibError::Errno => "zlib hit a file system error",
+            ZLibError::StreamError => "zlib stream state is inconsistent",
+            ZLibError::DataError => "zlib input data is corrupt",
+            ZLibError::MemError => "zlib ran out of memory",
+            ZLibError::BufError => "zlib could not make progress",
+            ZLibError::VersionError => "zlib library version is incompatible",
+        })
+    }
+}
+
+impl std::error::Error for ZLibError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(i32)]
 pub enum FlushMode {
     NoFlush = 0,
@@ -103,15 +150,90 @@ pub enum FlushMode {
     Trees = 6,
 }
 
+/// The framing a deflate stream can come wrapped in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZlibFormat {
+    Zlib,
+    Gzip,
+    /// No recognizable header - this is most likely a raw deflate stream
+    Raw,
+}
+
+/// Sniffs the format of a stream from its first two bytes. None if there are fewer than two.
+pub fn detect_format(first_bytes: &[u8]) -> Option<ZlibFormat> {
+    if first_bytes.len() < 2 {
+        return None;
+    }
+
+    let (cmf, flg) = (first_bytes[0], first_bytes[1]);
+    if cmf == 0x1f && flg == 0x8b {
+        Some(ZlibFormat::Gzip)
+    } else if cmf & 0x0f == 8 && cmf >> 4 <= 7 && ((cmf as u16) << 8 | flg as u16) % 31 == 0 {
+        // Deflate with a window of at most 32KB, and a valid header check
+        Some(ZlibFormat::Zlib)
+    } else {
+        Some(ZlibFormat::Raw)
+    }
+}
+
+macro_rules! checksum_accumulator {
+    ($name:ident, $f:ident, $init:expr) => {
+        /// A running checksum which can be fed data a chunk at a time
+        #[derive(Clone, Copy, Debug)]
+        pub struct $name {
+            value: c_ulong,
+        }
+
+        impl $name {
+            pub fn new() -> $name {
+                $name { value: $init }
+            }
+
+            pub fn update(&mut self, data: &[u8]) {
+                // zlib takes lengths as uints, so feed it in pieces it can take
+                for chunk in data.chunks(c_uint::max_value() as usize) {
+                    self.value = unsafe { $f(self.value, chunk.as_ptr(), chunk.len() as c_uint) };
+                }
+            }
+
+            /// Feeds everything remaining in the Buf into the checksum
+            pub fn update_buf<B: bytes::Buf>(&mut self, mut b: B) {
+                while b.has_remaining() {
+                    let len = {
+                        let chunk = b.bytes();
+                        self.update(chunk);
+                        chunk.len()
+                    };
+                    b.advance(len);
+                }
+            }
+
+            pub fn value(&self) -> u32 {
+                self.value as u32
+            }
+        }
+    };
+}
+
+checksum_accumulator!(Adler32, adler32, 1);
+checksum_accumulator!(Crc32, crc32, 0);
+
 pub trait ZlibOperator {
     fn reset(&mut self);
     fn process(&mut self, flush: FlushMode) -> Option<ZLibError>;
     fn strm(&self) -> &ZStream;
     fn strm_mut(&mut self) -> &mut ZStream;
+
+    /// True once the end of the stream has been reached, until the next reset. Once a stream has
+    /// ended, process will not consume any more input.
+    fn stream_ended(&self) -> bool {
+        false
+    }
 }
 
 pub struct Inflate {
     pub strm: Box<ZStream>,
+    ended: bool,
 }
 
 impl Drop for Inflate {
@@ -126,6 +248,7 @@ impl Inflate {
     pub fn new() -> Result<Inflate, ZLibError> {
         let mut i = Inflate {
             strm: unsafe { Box::<ZStream>::new_zeroed().assume_init() },
+            ended: false,
         };
 
         let errno = unsafe {
@@ -142,15 +265,77 @@ impl Inflate {
 
         Ok(i)
     }
+
+    /// Creates an Inflate with a window of 2^window_bits bytes rather than the default 32KB, which
+    /// saves memory on streams known to be small. window_bits ranges from 8 to 15, and must be at
+    /// least as large as the window the stream was deflated with, otherwise inflating will fail.
+    pub fn new_windowed(window_bits: i32) -> Result<Inflate, ZLibError> {
+        let mut i = Inflate {
+            strm: unsafe { Box::<ZStream>::new_zeroed().assume_init() },
+            ended: false,
+        };
+
+        let errno = unsafe {
+            inflateInit2_(
+                i.strm.as_mut(),
+                window_bits,
+                ZLIB_MAJ_VERSION.as_ptr() as *const i8,
+                size_of::<ZStream>() as i32,
+            )
+        };
+
+        if let Some(e) = ZLibError::lookup(errno) {
+            return Err(e);
+        }
+
+        Ok(i)
+    }
+
+    /// Copies the entire state of this stream, so that the copy can carry on from where this one is
+    /// independently. This is expensive - the copy gets its own allocation of the whole window.
+    pub fn try_clone(&self) -> Result<Inflate, ZLibError> {
+        let mut i = Inflate {
+            strm: unsafe { Box::<ZStream>::new_zeroed().assume_init() },
+            ended: false,
+        };
+
+        let errno = unsafe {
+            inflateCopy(
+                i.strm.as_mut(),
+                self.strm.as_ref() as *const ZStream as *mut ZStream,
+            )
+        };
+
+        if let Some(e) = ZLibError::lookup(errno) {
+            // Don't let drop end a stream which was never set up
+            i.strm.internal_state = std::ptr::null_mut();
+            return Err(e);
+        }
+
+        i.ended = self.ended;
+        Ok(i)
+    }
+
+    /// True when inflate is sitting between deflate blocks, which is where every sync or full
+    /// flush leaves it once all of the flushed input has been consumed
+    pub fn at_block_boundary(&self) -> bool {
+        // zlib sets this bit of data_type whenever it is waiting on the next block header
+        self.strm.data_type & 128 != 0
+    }
 }
 
 impl ZlibOperator for Inflate {
     fn reset(&mut self) {
         unsafe { inflateReset(self.strm.as_mut()) }
+        self.ended = false;
     }
 
     fn process(&mut self, flush: FlushMode) -> Option<ZLibError> {
-        ZLibError::lookup(unsafe { inflate(self.strm.as_mut(), flush as i32) })
+        let errno = unsafe { inflate(self.strm.as_mut(), flush as i32) };
+        if errno == Z_STREAM_END {
+            self.ended = true;
+        }
+        ZLibError::lookup(errno)
     }
 
     fn strm(&self) -> &ZStream {
@@ -160,6 +345,135 @@ impl ZlibOperator for Inflate {
     fn strm_mut(&mut self) -> &mut ZStream {
         &mut self.strm
     }
+
+    fn stream_ended(&self) -> bool {
+        self.ended
+    }
+}
+
+/// The checksum a raw deflate stream is followed by, for protocols which append their own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingChecksum {
+    Adler32,
+    Crc32,
+}
+
+enum RunningChecksum {
+    Adler32(Adler32),
+    Crc32(Crc32),
+}
+
+impl RunningChecksum {
+    fn new(kind: TrailingChecksum) -> RunningChecksum {
+        match kind {
+            TrailingChecksum::Adler32 => RunningChecksum::Adler32(Adler32::new()),
+            TrailingChecksum::Crc32 => RunningChecksum::Crc32(Crc32::new()),
+        }
+    }
+
+    fn kind(&self) -> TrailingChecksum {
+        match self {
+            RunningChecksum::Adler32(_) => TrailingChecksum::Adler32,
+            RunningChecksum::Crc32(_) => TrailingChecksum::Crc32,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            RunningChecksum::Adler32(a) => a.update(data),
+            RunningChecksum::Crc32(c) => c.update(data),
+        }
+    }
+
+    fn value(&self) -> u32 {
+        match self {
+            RunningChecksum::Adler32(a) => a.value(),
+            RunningChecksum::Crc32(c) => c.value(),
+        }
+    }
+}
+
+/// Inflates a raw deflate stream which is followed by a 4 byte, big endian checksum of what it
+/// inflates to. Once the deflate stream ends, the checksum is read out of the input and checked
+/// against the output, and process fails with DataError if they don't match. The stream is only
+/// considered ended once the checksum has been read and matched.
+pub struct CheckedInflate {
+    inflate: Inflate,
+    sum: RunningChecksum,
+    trailer: [u8; 4],
+    trailer_len: usize,
+}
+
+impl CheckedInflate {
+    pub fn new(checksum: TrailingChecksum) -> Result<CheckedInflate, ZLibError> {
+        Ok(CheckedInflate {
+            inflate: Inflate::new_windowed(-15)?,
+            sum: RunningChecksum::new(checksum),
+            trailer: [0; 4],
+            trailer_len: 0,
+        })
+    }
+
+    // Takes as much of the trailing checksum out of the input as is there, and checks it once all
+    // of it has arrived
+    fn read_trailer(&mut self) -> Option<ZLibError> {
+        let strm = self.inflate.strm.as_mut();
+        let take = std::cmp::min(4 - self.trailer_len, strm.avail_in as usize);
+        if take == 0 && self.trailer_len < 4 {
+            // Like inflate, complain when there is nothing to make progress with
+            return Some(ZLibError::BufError);
+        }
+
+        unsafe {
+            let input = std::slice::from_raw_parts(strm.next_in, take);
+            self.trailer[self.trailer_len..self.trailer_len + take].copy_from_slice(input);
+            strm.next_in = strm.next_in.add(take);
+        }
+        strm.avail_in -= take as c_uint;
+        strm.total_in += take;
+        self.trailer_len += take;
+
+        if self.trailer_len == 4 && u32::from_be_bytes(self.trailer) != self.sum.value() {
+            return Some(ZLibError::DataError);
+        }
+        None
+    }
+}
+
+impl ZlibOperator for CheckedInflate {
+    fn reset(&mut self) {
+        self.inflate.reset();
+        self.sum = RunningChecksum::new(self.sum.kind());
+        self.trailer_len = 0;
+    }
+
+    fn process(&mut self, flush: FlushMode) -> Option<ZLibError> {
+        if !self.inflate.stream_ended() {
+            let out_start = self.inflate.strm.next_out;
+            let e = self.inflate.process(flush);
+            let produced = self.inflate.strm.next_out as usize - out_start as usize;
+            self.sum
+                .update(unsafe { std::slice::from_raw_parts(out_start, produced) });
+
+            if !self.inflate.stream_ended() {
+                return e;
+            }
+        }
+
+        self.read_trailer()
+    }
+
+    fn strm(&self) -> &ZStream {
+        self.inflate.strm()
+    }
+
+    fn strm_mut(&mut self) -> &mut ZStream {
+        self.inflate.strm_mut()
+    }
+
+    fn stream_ended(&self) -> bool {
+        self.trailer_len == 4 && u32::from_be_bytes(self.trailer) == self.sum.value()
+    }
 }
 
 pub struct Deflate {
@@ -195,6 +509,87 @@ impl Deflate {
 
         Ok(i)
     }
+
+    /// Creates a Deflate with a window of 2^window_bits bytes rather than the default 32KB.
+    /// window_bits ranges from 9 to 15 - zlib quietly bumps 8 up to 9. Whatever inflates this
+    /// stream needs a window at least this large.
+    pub fn new_windowed(level: i32, window_bits: i32) -> Result<Deflate, ZLibError> {
+        let mut i = Deflate {
+            strm: unsafe { Box::new_zeroed().assume_init() },
+        };
+
+        let errno = unsafe {
+            deflateInit2_(
+                i.strm.as_mut(),
+                level,
+                Z_DEFLATED,
+                window_bits,
+                DEFAULT_MEM_LEVEL,
+                DEFAULT_STRATEGY,
+                ZLIB_MAJ_VERSION.as_ptr() as *const i8,
+                size_of::<ZStream>() as i32,
+            )
+        };
+
+        if let Some(e) = ZLibError::lookup(errno) {
+            return Err(e);
+        }
+
+        Ok(i)
+    }
+
+    /// Copies the entire state of this stream, so that the copy can carry on from where this one is
+    /// independently. This is expensive - the copy gets its own allocation of the whole window,
+    /// along with all of the other compression state.
+    pub fn try_clone(&self) -> Result<Deflate, ZLibError> {
+        let mut i = Deflate {
+            strm: unsafe { Box::new_zeroed().assume_init() },
+        };
+
+        let errno = unsafe {
+            deflateCopy(
+                i.strm.as_mut(),
+                self.strm.as_ref() as *const ZStream as *mut ZStream,
+            )
+        };
+
+        if let Some(e) = ZLibError::lookup(errno) {
+            // deflateCopy can fail after copying over the source's pointer to its state - make sure
+            // that drop doesn't end the source's stream out from under it
+            i.strm.internal_state = std::ptr::null_mut();
+            return Err(e);
+        }
+
+        Ok(i)
+    }
+
+    /// The number of bytes and bits of output deflate has generated, but not yet written out. If
+    /// these are both 0, there is nothing to gain from flushing aside from what is still held in
+    /// the window.
+    pub fn pending(&self) -> (usize, u32) {
+        let mut pending: c_uint = 0;
+        let mut bits: c_int = 0;
+        // deflatePending only reads from the stream, despite what its signature says
+        unsafe {
+            deflatePending(
+                self.strm.as_ref() as *const ZStream as *mut ZStream,
+                &mut pending,
+                &mut bits,
+            );
+        }
+        (pending as usize, bits as u32)
+    }
+}
+
+/// The worst case size that source_len bytes could deflate to with the given stream's settings
+pub fn deflate_bound(strm: &ZStream, source_len: usize) -> usize {
+    // deflateBound only reads from the stream, despite what its signature says
+    unsafe {
+        deflateBound(
+            strm as *const ZStream as *mut ZStream,
+            source_len as c_ulong,
+        ) as usize
+    }
 }
 
 impl ZlibOperator for Deflate {
@@ -214,3 +609,239 @@ impl ZlibOperator for Deflate {
         &mut self.strm
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_display() {
+        for e in [
+            ZLibError::Errno,
+            ZLibError::StreamError,
+            ZLibError::DataError,
+            ZLibError::MemError,
+            ZLibError::BufError,
+            ZLibError::VersionError,
+        ]
+        .iter()
+        {
+            assert!(e.to_string().starts_with("zlib "));
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xcbf43926);
+
+        let mut adler = Adler32::new();
+        adler.update_buf(bytes::Bytes::from_static(b"Wikipedia"));
+        assert_eq!(adler.value(), 0x11e60398);
+    }
+
+    // Runs all of input through the stream with a sync flush, appending whatever comes out
+    fn run<Z: ZlibOperator>(z: &mut Z, input: &[u8], out: &mut Vec<u8>) {
+        let mut buf = [0u8; 64];
+        z.strm_mut().next_in = input.as_ptr();
+        z.strm_mut().avail_in = input.len() as c_uint;
+        loop {
+            z.strm_mut().next_out = buf.as_mut_ptr();
+            z.strm_mut().avail_out = buf.len() as c_uint;
+            match z.process(FlushMode::SyncFlush) {
+                None | Some(ZLibError::BufError) => {}
+                Some(e) => panic!("zlib error {:?}", e),
+            }
+            let produced = buf.len() - z.strm().avail_out as usize;
+            out.extend_from_slice(&buf[..produced]);
+            if z.strm().avail_in == 0 && z.strm().avail_out != 0 {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn stream_copy() {
+        let prefix = b"the same old prefix, the same old prefix, ";
+        let mut deflate = Deflate::new(5).unwrap();
+        let mut a = Vec::new();
+        run(&mut deflate, prefix, &mut a);
+        let mut b = a.clone();
+
+        let mut fork = deflate.try_clone().unwrap();
+        run(&mut deflate, b"and then suffix a", &mut a);
+        run(&mut fork, b"but this is suffix b", &mut b);
+        drop(deflate);
+
+        // Fork the inflate side at the same point the deflate side was forked
+        let split = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+        let mut inflate = Inflate::new().unwrap();
+        let mut common = Vec::new();
+        run(&mut inflate, &a[..split], &mut common);
+        let mut inflate_fork = inflate.try_clone().unwrap();
+
+        let mut out_a = common.clone();
+        run(&mut inflate, &a[split..], &mut out_a);
+        assert_eq!(
+            &out_a[..],
+            &b"the same old prefix, the same old prefix, and then suffix a"[..]
+        );
+
+        let mut out_b = common;
+        run(&mut inflate_fork, &b[split..], &mut out_b);
+        assert_eq!(
+            &out_b[..],
+            &b"the same old prefix, the same old prefix, but this is suffix b"[..]
+        );
+    }
+
+    #[test]
+    fn deflate_pending() {
+        let mut deflate = Deflate::new(5).unwrap();
+        assert_eq!(deflate.pending(), (0, 0));
+
+        // Only leave room for one of the two header bytes
+        let input = b"pending pending";
+        let mut out = [0u8; 1];
+        deflate.strm.next_in = input.as_ptr();
+        deflate.strm.avail_in = input.len() as c_uint;
+        deflate.strm.next_out = out.as_mut_ptr();
+        deflate.strm.avail_out = 1;
+        assert_eq!(deflate.process(FlushMode::NoFlush), None);
+        assert_eq!(deflate.pending().0, 1);
+
+        // Nothing gets taken in while the header is stuck
+        let left = deflate.strm.avail_in as usize;
+        let mut rest = Vec::new();
+        run(&mut deflate, &input[input.len() - left..], &mut rest);
+        assert_eq!(deflate.pending(), (0, 0));
+
+        let mut stream = out.to_vec();
+        stream.extend_from_slice(&rest);
+        let mut inflated = Vec::new();
+        run(&mut Inflate::new().unwrap(), &stream, &mut inflated);
+        assert_eq!(&inflated[..], &input[..]);
+    }
+
+    #[test]
+    fn small_window() {
+        let input = b"a small stream, a small stream, a small stream";
+        let mut deflate = Deflate::new_windowed(5, 9).unwrap();
+        let mut compressed = Vec::new();
+        run(&mut deflate, input, &mut compressed);
+
+        let mut inflated = Vec::new();
+        run(
+            &mut Inflate::new_windowed(9).unwrap(),
+            &compressed,
+            &mut inflated,
+        );
+        assert_eq!(&inflated[..], &input[..]);
+
+        // A window smaller than the deflater's can't inflate the stream
+        let mut deflate = Deflate::new(5).unwrap();
+        let mut compressed = Vec::new();
+        run(&mut deflate, input, &mut compressed);
+
+        let mut inflate = Inflate::new_windowed(9).unwrap();
+        let mut out = [0u8; 64];
+        inflate.strm.next_in = compressed.as_ptr();
+        inflate.strm.avail_in = compressed.len() as c_uint;
+        inflate.strm.next_out = out.as_mut_ptr();
+        inflate.strm.avail_out = out.len() as c_uint;
+        assert_eq!(
+            inflate.process(FlushMode::SyncFlush),
+            Some(ZLibError::DataError)
+        );
+    }
+
+    #[test]
+    fn checked_inflate() {
+        let input = b"checked, checked, checked and checked again";
+
+        // A raw deflate stream, followed by the checksum of its contents
+        let stream = |kind: TrailingChecksum| {
+            let mut deflate = Deflate::new_windowed(5, -15).unwrap();
+            let mut compressed = vec![0u8; 128];
+            deflate.strm.next_in = input.as_ptr();
+            deflate.strm.avail_in = input.len() as c_uint;
+            deflate.strm.next_out = compressed.as_mut_ptr();
+            deflate.strm.avail_out = compressed.len() as c_uint;
+            assert_eq!(deflate.process(FlushMode::Finish), None);
+            compressed.truncate(deflate.strm.total_out);
+
+            let sum = match kind {
+                TrailingChecksum::Adler32 => {
+                    let mut a = Adler32::new();
+                    a.update(input);
+                    a.value()
+                }
+                TrailingChecksum::Crc32 => {
+                    let mut c = Crc32::new();
+                    c.update(input);
+                    c.value()
+                }
+            };
+            compressed.extend_from_slice(&sum.to_be_bytes());
+            compressed
+        };
+
+        for &kind in [TrailingChecksum::Adler32, TrailingChecksum::Crc32].iter() {
+            let compressed = stream(kind);
+            let mut inflate = CheckedInflate::new(kind).unwrap();
+            let mut inflated = Vec::new();
+            // Feed it in two halves, so the trailer can arrive separately from the stream
+            let (a, b) = compressed.split_at(compressed.len() - 2);
+            run(&mut inflate, a, &mut inflated);
+            assert!(!inflate.stream_ended());
+            run(&mut inflate, b, &mut inflated);
+            assert!(inflate.stream_ended());
+            assert_eq!(&inflated[..], &input[..]);
+
+            // And again with a corrupted checksum
+            let mut corrupt = compressed.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            inflate.reset();
+            let mut out = [0u8; 128];
+            inflate.strm_mut().next_in = corrupt.as_ptr();
+            inflate.strm_mut().avail_in = corrupt.len() as c_uint;
+            inflate.strm_mut().next_out = out.as_mut_ptr();
+            inflate.strm_mut().avail_out = out.len() as c_uint;
+            assert_eq!(
+                inflate.process(FlushMode::SyncFlush),
+                Some(ZLibError::DataError)
+            );
+            assert!(!inflate.stream_ended());
+        }
+    }
+
+    #[test]
+    fn format_detection() {
+        assert_eq!(detect_format(&[0x1f]), None);
+        assert_eq!(detect_format(&[0x1f, 0x8b, 0x08]), Some(ZlibFormat::Gzip));
+        for header in [
+            [0x78, 0x01],
+            [0x78, 0x5e],
+            [0x78, 0x9c],
+            [0x78, 0xda],
+            [0x48, 0x0d],
+        ]
+        .iter()
+        {
+            assert_eq!(detect_format(header), Some(ZlibFormat::Zlib));
+        }
+
+        // What Deflate::new actually writes
+        let mut compressed = Vec::new();
+        run(&mut Deflate::new(5).unwrap(), b"sniff", &mut compressed);
+        assert_eq!(detect_format(&compressed), Some(ZlibFormat::Zlib));
+
+        // Raw deflate blocks - a fixed huffman block, and a stored block
+        assert_eq!(detect_format(&[0x4b, 0x4c]), Some(ZlibFormat::Raw));
+        assert_eq!(detect_format(&[0x01, 0x05]), Some(ZlibFormat::Raw));
+        // Right method, bad check bits
+        assert_eq!(detect_format(&[0x78, 0x9d]), Some(ZlibFormat::Raw));
+    }
+}
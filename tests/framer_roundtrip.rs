@@ -0,0 +1,185 @@
+/*
+ *  Copyright (C) 2020  Joe Hirschfeld <j@ibj.io>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Serializes random runs of frames, chops the bytes up at random points and feeds the pieces to a
+//! Framer, checking that exactly the original frames come back out. Every run is seeded, so a
+//! failure can be replayed by its seed.
+
+use blockrouter::framer::{FrameError, Framer};
+use blockrouter::parser;
+use bytes::{Buf, Bytes};
+
+const MAX_FRAME_SIZE: usize = 1 << 16;
+
+/// A xorshift64* generator - plenty random enough to shake out framing bugs, and reproducible
+/// without pulling in a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // A zero state would only ever produce zeros
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform-ish in [lo, hi)
+    fn range(&mut self, lo: usize, hi: usize) -> usize {
+        lo + (self.next() % (hi - lo) as u64) as usize
+    }
+}
+
+fn random_frames(rng: &mut Rng) -> Vec<Vec<u8>> {
+    let count = rng.range(1, 64);
+    (0..count)
+        .map(|_| {
+            // Mostly small frames, with the occasional one large enough to span a lot of pieces
+            let len = match rng.range(0, 8) {
+                0 => rng.range(0, MAX_FRAME_SIZE),
+                // Empty frames are legal, and worth a few extra
+                1 => 0,
+                _ => rng.range(0, 300),
+            };
+            (0..len).map(|_| rng.next() as u8).collect()
+        })
+        .collect()
+}
+
+fn serialize(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for f in frames {
+        let mut header = [0; 5];
+        let n = parser::encode_varint(f.len() as i32, &mut &mut header[..]);
+        out.extend_from_slice(&header[..n]);
+        out.extend_from_slice(f);
+    }
+    out
+}
+
+/// Splits data into pieces, favouring tiny ones so that headers and bodies get cut mid-way
+fn fragment(rng: &mut Rng, data: &[u8]) -> Vec<Bytes> {
+    let mut pieces = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let len = match rng.range(0, 4) {
+            0 => 1,
+            1 => rng.range(1, 8),
+            2 => rng.range(1, 512),
+            _ => rng.range(1, 8192),
+        };
+        let end = std::cmp::min(at + len, data.len());
+        pieces.push(Bytes::copy_from_slice(&data[at..end]));
+        at = end;
+    }
+    pieces
+}
+
+fn round_trip(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let frames = random_frames(&mut rng);
+    let data = serialize(&frames);
+    let pieces = fragment(&mut rng, &data);
+
+    // Where each frame ends in data, so that WaitingForData hints can be checked
+    let mut ends = Vec::with_capacity(frames.len());
+    let mut end = 0;
+    for f in &frames {
+        let mut header = [0; 5];
+        end += parser::encode_varint(f.len() as i32, &mut &mut header[..]) + f.len();
+        ends.push(end);
+    }
+
+    let mut framer = Framer::new(MAX_FRAME_SIZE, 16);
+    let mut pushed = 0;
+    let mut next = 0;
+    for piece in pieces {
+        pushed += piece.len();
+        framer.push_buffer(piece);
+        loop {
+            match framer.frame() {
+                Ok(frame) => {
+                    assert!(next < frames.len(), "seed {}: extra frame", seed);
+                    assert_eq!(
+                        frame.payload_len(),
+                        frames[next].len(),
+                        "seed {}: frame {} length",
+                        seed,
+                        next
+                    );
+                    let body = frame.into_body();
+                    assert_eq!(body.remaining(), frames[next].len());
+                    let mut got = Vec::with_capacity(body.remaining());
+                    for page in body {
+                        got.extend_from_slice(&page);
+                    }
+                    assert_eq!(got, frames[next], "seed {}: frame {} body", seed, next);
+                    next += 1;
+                }
+                Err(FrameError::WaitingForHeader) => break,
+                Err(FrameError::WaitingForData(n)) => {
+                    assert_eq!(
+                        n,
+                        ends[next] - pushed,
+                        "seed {}: frame {} missing byte hint",
+                        seed,
+                        next
+                    );
+                    break;
+                }
+                Err(FrameError::DecodeError(e)) => panic!("seed {}: decode error: {}", seed, e),
+            }
+        }
+    }
+
+    assert_eq!(next, frames.len(), "seed {}: frames missing", seed);
+    assert_eq!(framer.buffered(), 0, "seed {}: data left over", seed);
+}
+
+#[test]
+fn framer_round_trip() {
+    for seed in 0..256 {
+        round_trip(seed);
+    }
+}
+
+#[test]
+fn framer_round_trip_single_bytes() {
+    // Every byte pushed on its own is the worst case for the cross-page paths
+    let mut rng = Rng::new(0xb10c);
+    let mut frames = random_frames(&mut rng);
+    frames.truncate(4);
+    let data = serialize(&frames);
+
+    let mut framer = Framer::new(MAX_FRAME_SIZE, 16);
+    let mut got = Vec::new();
+    for b in data.iter() {
+        framer.push_buffer(Bytes::copy_from_slice(&[*b]));
+        while let Ok(frame) = framer.frame() {
+            let mut body = Vec::new();
+            for page in frame.into_body() {
+                body.extend_from_slice(&page);
+            }
+            got.push(body);
+        }
+    }
+    assert_eq!(got, frames);
+}